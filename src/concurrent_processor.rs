@@ -0,0 +1,258 @@
+use crate::error::UploadError;
+use bytes::Bytes;
+use futures::channel::oneshot;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Mutex, MutexGuard},
+};
+use tracing::{debug, instrument};
+
+type Outcome = Result<Option<Bytes>, SharedError>;
+
+// UploadError can't be cloned, so waiting requests get a copy of the parts that decide how the
+// failure is reported
+#[derive(Clone, Debug)]
+enum SharedError {
+    Busy,
+    Timeout,
+    ResourceLimit(String),
+    Rejected(String),
+    ImageTooLarge(String),
+    Other(String),
+}
+
+impl From<&UploadError> for SharedError {
+    fn from(e: &UploadError) -> Self {
+        match e {
+            UploadError::Busy => SharedError::Busy,
+            UploadError::Timeout => SharedError::Timeout,
+            UploadError::ResourceLimit(msg) => SharedError::ResourceLimit(msg.clone()),
+            UploadError::Rejected(msg) => SharedError::Rejected(msg.clone()),
+            UploadError::ImageTooLarge(msg) => SharedError::ImageTooLarge(msg.clone()),
+            UploadError::Process(msg) => SharedError::Other(msg.clone()),
+            e => SharedError::Other(e.to_string()),
+        }
+    }
+}
+
+impl From<SharedError> for UploadError {
+    fn from(e: SharedError) -> Self {
+        match e {
+            SharedError::Busy => UploadError::Busy,
+            SharedError::Timeout => UploadError::Timeout,
+            SharedError::ResourceLimit(msg) => UploadError::ResourceLimit(msg),
+            SharedError::Rejected(msg) => UploadError::Rejected(msg),
+            SharedError::ImageTooLarge(msg) => UploadError::ImageTooLarge(msg),
+            SharedError::Other(msg) => UploadError::Process(msg),
+        }
+    }
+}
+
+enum Entry {
+    // The variant is being processed, these requests are waiting on the result
+    Processing(Vec<oneshot::Sender<Outcome>>),
+
+    // The variant has been processed, but isn't in the store yet
+    Saving(Bytes),
+}
+
+static PROCESS_MAP: Lazy<Mutex<HashMap<String, Entry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn process_map() -> MutexGuard<'static, HashMap<String, Entry>> {
+    PROCESS_MAP.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Make sure waiters aren't left hanging if the processing request goes away early
+struct MapGuard {
+    key: Option<String>,
+}
+
+impl MapGuard {
+    fn disarm(mut self) -> String {
+        self.key.take().expect("Guard is only disarmed once")
+    }
+}
+
+impl Drop for MapGuard {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            debug!("Processing for {} was dropped", key);
+            // dropping the senders wakes every waiter, and they retry
+            process_map().remove(&key);
+        }
+    }
+}
+
+/// Process a variant, making sure only one request processes a given key at a time
+///
+/// The first request for a key runs `process`, while any requests arriving before the result has
+/// been saved wait for and share that result. If the processing request goes away before
+/// finishing, one of the waiting requests takes over. If `process` produced new bytes, `save` is
+/// spawned in the background and the key is released once it completes.
+#[instrument(skip(process, save))]
+pub(crate) async fn process<P, PFut, S, SFut>(
+    key: String,
+    process: P,
+    save: S,
+) -> Result<Option<Bytes>, UploadError>
+where
+    P: FnOnce() -> PFut,
+    PFut: Future<Output = Result<Option<Bytes>, UploadError>>,
+    S: FnOnce(Bytes) -> SFut + 'static,
+    SFut: Future<Output = ()> + 'static,
+{
+    loop {
+        let rx = {
+            let mut map = process_map();
+
+            match map.get_mut(&key) {
+                Some(Entry::Processing(waiters)) => {
+                    let (tx, rx) = oneshot::channel();
+                    waiters.push(tx);
+                    rx
+                }
+                Some(Entry::Saving(bytes)) => {
+                    debug!("Using processed bytes waiting to be saved");
+                    return Ok(Some(bytes.clone()));
+                }
+                None => {
+                    map.insert(key.clone(), Entry::Processing(Vec::new()));
+                    break;
+                }
+            }
+        };
+
+        debug!("Waiting on in-flight processing");
+        match rx.await {
+            Ok(outcome) => return outcome.map_err(UploadError::from),
+            Err(_) => debug!("In-flight processing was dropped, retrying"),
+        }
+    }
+
+    let guard = MapGuard {
+        key: Some(key.clone()),
+    };
+
+    let res = process().await;
+
+    let key = guard.disarm();
+    let outcome = match &res {
+        Ok(opt) => Ok(opt.clone()),
+        Err(e) => Err(SharedError::from(e)),
+    };
+
+    let waiters = {
+        let mut map = process_map();
+
+        let waiters = match map.remove(&key) {
+            Some(Entry::Processing(waiters)) => waiters,
+            _ => Vec::new(),
+        };
+
+        if let Ok(Some(bytes)) = &res {
+            map.insert(key.clone(), Entry::Saving(bytes.clone()));
+        }
+
+        waiters
+    };
+
+    debug!("Sharing result with {} waiting requests", waiters.len());
+    for tx in waiters {
+        let _ = tx.send(outcome.clone());
+    }
+
+    if let Ok(Some(bytes)) = &res {
+        let bytes = bytes.clone();
+        actix_rt::spawn(async move {
+            save(bytes).await;
+            process_map().remove(&key);
+        });
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_rt::time::{delay_for, timeout};
+    use futures::future::{join, join_all};
+    use std::{cell::Cell, time::Duration};
+
+    fn key() -> String {
+        uuid::Uuid::new_v4().to_string()
+    }
+
+    #[actix_rt::test]
+    async fn processes_once() {
+        let key = key();
+        let count = Cell::new(0);
+
+        let results = join_all((0..8).map(|_| {
+            process(
+                key.clone(),
+                || async {
+                    count.set(count.get() + 1);
+                    delay_for(Duration::from_millis(50)).await;
+                    Ok(Some(Bytes::from_static(b"variant")))
+                },
+                |_| async {},
+            )
+        }))
+        .await;
+
+        assert_eq!(count.get(), 1);
+        for res in results {
+            assert_eq!(res.unwrap(), Some(Bytes::from_static(b"variant")));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn waiter_takes_over() {
+        let key = key();
+
+        let leader = timeout(
+            Duration::from_millis(50),
+            process(key.clone(), || futures::future::pending(), |_| async {}),
+        );
+        let waiter = async {
+            delay_for(Duration::from_millis(10)).await;
+            process(
+                key.clone(),
+                || async { Ok(Some(Bytes::from_static(b"variant"))) },
+                |_| async {},
+            )
+            .await
+        };
+
+        let (leader, waiter) = join(leader, waiter).await;
+
+        assert!(leader.is_err());
+        assert_eq!(waiter.unwrap(), Some(Bytes::from_static(b"variant")));
+    }
+
+    #[actix_rt::test]
+    async fn shares_error_kind() {
+        let key = key();
+
+        let leader = process(
+            key.clone(),
+            || async {
+                delay_for(Duration::from_millis(50)).await;
+                Err(UploadError::ResourceLimit("too big".to_owned()))
+            },
+            |_| async {},
+        );
+        let waiter = async {
+            delay_for(Duration::from_millis(10)).await;
+            process(key.clone(), || async { Ok(None) }, |_| async {}).await
+        };
+
+        let (leader, waiter) = join(leader, waiter).await;
+
+        assert!(matches!(leader, Err(UploadError::ResourceLimit(_))));
+        assert!(matches!(waiter, Err(UploadError::ResourceLimit(_))));
+    }
+}
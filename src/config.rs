@@ -1,4 +1,17 @@
-use std::{collections::HashSet, net::SocketAddr, path::PathBuf};
+use crate::{
+    download::DownloadPolicy,
+    processor::{parse_dimensions, Limits},
+    store::StoreConfig,
+    telemetry::LogConfig,
+    validate::ImageLimits,
+    MEGABYTES,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::PathBuf,
+    time::Duration,
+};
 
 #[derive(Clone, Debug, structopt::StructOpt)]
 pub(crate) struct Config {
@@ -18,6 +31,13 @@ pub(crate) struct Config {
     )]
     addr: SocketAddr,
 
+    #[structopt(
+        long,
+        env = "PICTRS_INTERNAL_ADDR",
+        help = "An optional address and port to serve internal endpoints like /import on, instead of the main address"
+    )]
+    internal_addr: Option<SocketAddr>,
+
     #[structopt(
         short,
         long,
@@ -38,7 +58,7 @@ pub(crate) struct Config {
         short,
         long,
         env = "PICTRS_FILTER_WHITELIST",
-        help = "An optional list of filters to whitelist, supports 'identity', 'thumbnail', and 'blur'"
+        help = "An optional list of filters to whitelist, supports 'identity', 'thumbnail', 'resize', 'crop', 'fill', 'blur', and 'format'"
     )]
     whitelist: Option<Vec<String>>,
 
@@ -50,6 +70,458 @@ pub(crate) struct Config {
         default_value = "40"
     )]
     max_file_size: usize,
+
+    #[structopt(
+        long,
+        env = "PICTRS_NEGOTIATE_FORMAT",
+        help = "Serve webp versions of images to clients that accept them, unless the request asks for a specific format"
+    )]
+    negotiate_format: bool,
+
+    #[structopt(
+        long,
+        env = "PICTRS_STRICT_TRANSFORMATIONS",
+        help = "Respond with an error when a request contains an invalid or non-whitelisted transformation, rather than skipping it"
+    )]
+    strict_transformations: bool,
+
+    #[structopt(
+        long,
+        env = "PICTRS_MAX_IMAGE_WIDTH",
+        help = "The widest image that can be uploaded, in pixels",
+        default_value = "10000"
+    )]
+    max_image_width: usize,
+
+    #[structopt(
+        long,
+        env = "PICTRS_MAX_IMAGE_HEIGHT",
+        help = "The tallest image that can be uploaded, in pixels",
+        default_value = "10000"
+    )]
+    max_image_height: usize,
+
+    #[structopt(
+        long,
+        env = "PICTRS_MAX_IMAGE_AREA",
+        help = "The most pixels an uploaded image can contain",
+        default_value = "40000000"
+    )]
+    max_image_area: usize,
+
+    #[structopt(
+        long,
+        env = "PICTRS_MAX_GIF_FRAMES",
+        help = "The most frames an uploaded gif can contain",
+        default_value = "500"
+    )]
+    max_gif_frames: usize,
+
+    #[structopt(
+        long,
+        env = "PICTRS_MAX_THUMBNAIL_SIZE",
+        help = "The largest size accepted by the thumbnail transformation",
+        default_value = "4096"
+    )]
+    max_thumbnail_size: usize,
+
+    #[structopt(
+        long,
+        env = "PICTRS_THUMBNAIL_SIZES",
+        help = "An optional list of the only sizes accepted by the thumbnail transformation, e.g. 64 128 256 512"
+    )]
+    thumbnail_sizes: Option<Vec<usize>>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_MAX_DIMENSION",
+        help = "The largest width or height accepted by the resize, crop, and fill transformations",
+        default_value = "4096"
+    )]
+    max_dimension: usize,
+
+    #[structopt(
+        long,
+        env = "PICTRS_DIMENSIONS",
+        help = "An optional list of the only sizes accepted by the resize, crop, and fill transformations, e.g. 256x256 1920x1080"
+    )]
+    dimensions: Option<Vec<Dimensions>>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_MAX_BLUR_SIGMA",
+        help = "The largest sigma accepted by the blur transformation",
+        default_value = "20"
+    )]
+    max_blur_sigma: f64,
+
+    #[structopt(
+        long,
+        env = "PICTRS_BLUR_SIGMAS",
+        help = "An optional list of the only sigmas accepted by the blur transformation, e.g. 1 2.5 5"
+    )]
+    blur_sigmas: Option<Vec<f64>>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_MAX_CHAIN_LENGTH",
+        help = "The most transformations that can be applied in a single request",
+        default_value = "10"
+    )]
+    max_chain_length: usize,
+
+    #[structopt(
+        long,
+        env = "PICTRS_MAGICK_THREADS",
+        help = "How many threads to dedicate to image processing. Default: the number of CPUs"
+    )]
+    magick_threads: Option<usize>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_MAGICK_QUEUE_SIZE",
+        help = "How many image processing jobs can wait for a thread before requests are rejected",
+        default_value = "32"
+    )]
+    magick_queue_size: usize,
+
+    #[structopt(
+        long,
+        env = "PICTRS_MAGICK_TIMEOUT",
+        help = "How many seconds an image processing operation can take before its request gives up. Only sandboxed operations are stopped, others keep their thread until they finish",
+        default_value = "30"
+    )]
+    magick_timeout: u64,
+
+    #[structopt(
+        long,
+        env = "PICTRS_MAGICK_MEMORY_LIMIT",
+        help = "How much memory ImageMagick can use for pixel data, in Megabytes",
+        default_value = "256"
+    )]
+    magick_memory_limit: usize,
+
+    #[structopt(
+        long,
+        env = "PICTRS_MAGICK_MAP_LIMIT",
+        help = "How much memory-mapped pixel data ImageMagick can use, in Megabytes",
+        default_value = "512"
+    )]
+    magick_map_limit: usize,
+
+    #[structopt(
+        long,
+        env = "PICTRS_MAGICK_DISK_LIMIT",
+        help = "How much disk space ImageMagick can use for pixel data, in Megabytes",
+        default_value = "1024"
+    )]
+    magick_disk_limit: usize,
+
+    #[structopt(
+        long = "preset",
+        env = "PICTRS_PRESETS",
+        help = "A named transformation chain served at /image/preset/{name}/{file}, e.g. avatar=thumbnail128 or hero=resize1920x1080/formatwebp"
+    )]
+    presets: Vec<Preset>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_PRESETS_ONLY",
+        help = "Only allow transformations through presets, rejecting requests for arbitrary chains"
+    )]
+    presets_only: bool,
+
+    #[structopt(
+        long,
+        env = "PICTRS_SANDBOX",
+        help = "Decode images in a short-lived child process, so a crash in a decoder can't take down the server"
+    )]
+    sandbox: bool,
+
+    #[structopt(
+        long,
+        env = "PICTRS_SIGNING_KEY",
+        help = "An optional key used to verify the 'sig' query parameter on requests for transformed images",
+        hide_env_values = true
+    )]
+    signing_key: Option<String>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_ALLOW_UNSIGNED_ORIGINALS",
+        help = "Whether to serve original images without a signature when a signing key is set"
+    )]
+    allow_unsigned_originals: bool,
+
+    #[structopt(
+        long = "api-key",
+        env = "PICTRS_API_KEYS",
+        help = "A key clients can send in the X-Api-Token header to use /import and other internal endpoints, which are open when none are set",
+        hide_env_values = true
+    )]
+    api_keys: Vec<String>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_DOWNLOAD",
+        help = "Who can use the download endpoint, supports 'open', 'api-key', and 'disabled'",
+        default_value = "open"
+    )]
+    download: DownloadMode,
+
+    #[structopt(
+        long,
+        env = "PICTRS_DOWNLOAD_MAX_CONCURRENT",
+        help = "How many images can be downloading at once",
+        default_value = "16"
+    )]
+    download_max_concurrent: usize,
+
+    #[structopt(
+        long,
+        env = "PICTRS_DOWNLOAD_MAX_SIZE",
+        help = "The largest image that can be downloaded (in Megabytes). Default: the maximum file size"
+    )]
+    download_max_size: Option<usize>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_DOWNLOAD_ALLOW_PRIVATE",
+        help = "Allow downloading images from loopback, link-local and private addresses"
+    )]
+    download_allow_private: bool,
+
+    #[structopt(
+        long,
+        env = "PICTRS_DOWNLOAD_ALLOWED_DOMAINS",
+        help = "An optional list of the only domains images can be downloaded from, including their subdomains"
+    )]
+    download_allowed_domains: Option<Vec<String>>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_DOWNLOAD_DENIED_DOMAINS",
+        help = "An optional list of domains images can't be downloaded from, including their subdomains"
+    )]
+    download_denied_domains: Vec<String>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_DOWNLOAD_MAX_REDIRECTS",
+        help = "How many redirects to follow when downloading an image",
+        default_value = "5"
+    )]
+    download_max_redirects: usize,
+
+    #[structopt(
+        long,
+        env = "PICTRS_DOWNLOAD_TIMEOUT",
+        help = "How many seconds downloading an image can take",
+        default_value = "30"
+    )]
+    download_timeout: u64,
+
+    #[structopt(
+        long,
+        env = "PICTRS_DELETE_TOKEN_LENGTH",
+        help = "How many characters long new delete tokens are",
+        default_value = "32"
+    )]
+    delete_token_length: usize,
+
+    #[structopt(
+        long,
+        env = "PICTRS_DISABLE_GET_DELETE",
+        help = "Only allow deleting images with DELETE requests, not GET requests"
+    )]
+    disable_get_delete: bool,
+
+    #[structopt(
+        long,
+        env = "PICTRS_SHUTDOWN_DELAY",
+        help = "How many seconds /readyz reports not ready on SIGINT or SIGTERM before the server stops",
+        default_value = "5"
+    )]
+    shutdown_delay: u64,
+
+    #[structopt(
+        long,
+        env = "PICTRS_LOG_FORMAT",
+        help = "How to format log lines, supports 'normal' and 'json'",
+        default_value = "normal"
+    )]
+    log_format: LogFormat,
+
+    #[structopt(
+        long,
+        env = "PICTRS_LOG_FILE",
+        help = "An optional file to write logs to instead of stdout, e.g. logs/pict-rs.log"
+    )]
+    log_file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_LOG_ROTATION",
+        help = "How often to start a new log file, supports 'hourly', 'daily', and 'never'",
+        default_value = "daily"
+    )]
+    log_rotation: LogRotation,
+
+    #[structopt(
+        long,
+        env = "PICTRS_OPENTELEMETRY_URL",
+        help = "An optional OTLP collector to export traces to over gRPC, e.g. localhost:4317"
+    )]
+    opentelemetry_url: Option<String>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_STORE",
+        help = "Where to keep uploaded files, supports 'file' and 's3'",
+        default_value = "file"
+    )]
+    store: StoreKind,
+
+    #[structopt(
+        long,
+        env = "PICTRS_S3_ENDPOINT",
+        help = "The base URL of the S3-compatible object storage, e.g. http://localhost:9000",
+        required_if("store", "s3")
+    )]
+    s3_endpoint: Option<String>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_S3_BUCKET",
+        help = "The bucket to store files in",
+        required_if("store", "s3")
+    )]
+    s3_bucket: Option<String>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_S3_REGION",
+        help = "The region the bucket lives in",
+        default_value = "us-east-1"
+    )]
+    s3_region: String,
+
+    #[structopt(
+        long,
+        env = "PICTRS_S3_ACCESS_KEY",
+        help = "The access key used to authenticate with the object storage",
+        required_if("store", "s3")
+    )]
+    s3_access_key: Option<String>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_S3_SECRET_KEY",
+        help = "The secret key used to authenticate with the object storage",
+        required_if("store", "s3"),
+        hide_env_values = true
+    )]
+    s3_secret_key: Option<String>,
+
+    #[structopt(
+        long,
+        env = "PICTRS_S3_TIMEOUT",
+        help = "How many seconds a request to the object storage can take, including sending the file",
+        default_value = "300"
+    )]
+    s3_timeout: u64,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Debug, structopt::StructOpt)]
+pub(crate) enum Command {
+    #[structopt(
+        name = "migrate-store",
+        about = "Copy all originals and variants into a different store, then exit"
+    )]
+    MigrateStore {
+        #[structopt(
+            long,
+            help = "The store to copy from, either a directory or 's3' to use the configured bucket"
+        )]
+        from: StoreArg,
+
+        #[structopt(
+            long,
+            help = "The store to copy to, either a directory or 's3' to use the configured bucket"
+        )]
+        to: StoreArg,
+    },
+
+    #[structopt(
+        name = "sandbox-validate",
+        setting = structopt::clap::AppSettings::Hidden
+    )]
+    SandboxValidate {
+        #[structopt(long)]
+        format: Option<Format>,
+
+        #[structopt(long)]
+        max_width: usize,
+
+        #[structopt(long)]
+        max_height: usize,
+
+        #[structopt(long)]
+        max_area: usize,
+
+        #[structopt(long)]
+        max_frame_count: usize,
+    },
+
+    #[structopt(
+        name = "sandbox-process",
+        setting = structopt::clap::AppSettings::Hidden
+    )]
+    SandboxProcess { segments: Vec<String> },
+
+    #[structopt(name = "sandbox-info", setting = structopt::clap::AppSettings::Hidden)]
+    SandboxInfo,
+}
+
+impl Command {
+    /// Whether this process is a child spawned to decode images
+    pub(crate) fn is_sandbox(&self) -> bool {
+        match self {
+            Command::SandboxValidate { .. }
+            | Command::SandboxProcess { .. }
+            | Command::SandboxInfo => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum StoreArg {
+    Directory(PathBuf),
+    S3,
+}
+
+impl std::str::FromStr for StoreArg {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "s3" => Ok(StoreArg::S3),
+            other => Ok(StoreArg::Directory(PathBuf::from(other))),
+        }
+    }
+}
+
+impl std::fmt::Display for StoreArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StoreArg::Directory(path) => write!(f, "{}", path.display()),
+            StoreArg::S3 => write!(f, "s3"),
+        }
+    }
 }
 
 impl Config {
@@ -57,6 +529,10 @@ impl Config {
         self.addr
     }
 
+    pub(crate) fn internal_address(&self) -> Option<SocketAddr> {
+        self.internal_addr
+    }
+
     pub(crate) fn data_dir(&self) -> PathBuf {
         self.path.clone()
     }
@@ -78,6 +554,315 @@ impl Config {
     pub(crate) fn max_file_size(&self) -> usize {
         self.max_file_size
     }
+
+    pub(crate) fn negotiate_format(&self) -> bool {
+        self.negotiate_format
+    }
+
+    pub(crate) fn strict_transformations(&self) -> bool {
+        self.strict_transformations
+    }
+
+    pub(crate) fn image_limits(&self) -> ImageLimits {
+        ImageLimits {
+            max_width: self.max_image_width,
+            max_height: self.max_image_height,
+            max_area: self.max_image_area,
+            max_frame_count: self.max_gif_frames,
+        }
+    }
+
+    pub(crate) fn processor_limits(&self) -> Limits {
+        Limits {
+            max_thumbnail_size: self.max_thumbnail_size,
+            thumbnail_sizes: self
+                .thumbnail_sizes
+                .as_ref()
+                .map(|sizes| sizes.iter().cloned().collect()),
+            max_dimension: self.max_dimension,
+            dimensions: self.dimensions.as_ref().map(|dimensions| {
+                dimensions
+                    .iter()
+                    .map(|Dimensions(width, height)| (*width, *height))
+                    .collect()
+            }),
+            max_blur_sigma: self.max_blur_sigma,
+            blur_sigmas: self.blur_sigmas.clone(),
+            max_chain_length: self.max_chain_length,
+        }
+    }
+
+    pub(crate) fn magick_threads(&self) -> usize {
+        self.magick_threads.unwrap_or_else(num_cpus::get)
+    }
+
+    pub(crate) fn magick_queue_size(&self) -> usize {
+        self.magick_queue_size
+    }
+
+    pub(crate) fn magick_timeout(&self) -> Duration {
+        Duration::from_secs(self.magick_timeout)
+    }
+
+    /// ImageMagick's resource limits, as the environment variables it reads at startup
+    ///
+    /// This leaves out MAGICK_TIME_LIMIT, since ImageMagick exits the whole process when it's
+    /// reached. Only sandboxed children are given one.
+    pub(crate) fn magick_limits(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("MAGICK_MEMORY_LIMIT", format!("{}MiB", self.magick_memory_limit)),
+            ("MAGICK_MAP_LIMIT", format!("{}MiB", self.magick_map_limit)),
+            ("MAGICK_DISK_LIMIT", format!("{}MiB", self.magick_disk_limit)),
+        ]
+    }
+
+    pub(crate) fn presets(&self) -> Presets {
+        self.presets
+            .iter()
+            .map(|preset| (preset.name.clone(), preset.segments.clone()))
+            .collect()
+    }
+
+    pub(crate) fn presets_only(&self) -> bool {
+        self.presets_only
+    }
+
+    pub(crate) fn sandbox(&self) -> bool {
+        self.sandbox
+    }
+
+    pub(crate) fn signing_key(&self) -> Option<&str> {
+        self.signing_key.as_deref()
+    }
+
+    pub(crate) fn allow_unsigned_originals(&self) -> bool {
+        self.allow_unsigned_originals
+    }
+
+    pub(crate) fn download_policy(&self) -> DownloadPolicy {
+        DownloadPolicy {
+            allow_private: self.download_allow_private,
+            allowed_domains: self
+                .download_allowed_domains
+                .as_ref()
+                .map(|domains| domains.iter().cloned().collect()),
+            denied_domains: self.download_denied_domains.iter().cloned().collect(),
+            max_redirects: self.download_max_redirects,
+            timeout: Duration::from_secs(self.download_timeout),
+            max_concurrent: self.download_max_concurrent,
+            max_size: self.download_max_size.unwrap_or(self.max_file_size) * MEGABYTES,
+        }
+    }
+
+    pub(crate) fn api_keys(&self) -> Vec<String> {
+        self.api_keys.clone()
+    }
+
+    pub(crate) fn download_mode(&self) -> DownloadMode {
+        self.download.clone()
+    }
+
+    pub(crate) fn delete_token_length(&self) -> usize {
+        self.delete_token_length
+    }
+
+    pub(crate) fn allow_get_delete(&self) -> bool {
+        !self.disable_get_delete
+    }
+
+    pub(crate) fn shutdown_delay(&self) -> Duration {
+        Duration::from_secs(self.shutdown_delay)
+    }
+
+    pub(crate) fn log_config(&self) -> LogConfig {
+        LogConfig {
+            format: self.log_format.clone(),
+            file: self.log_file.clone(),
+            rotation: self.log_rotation.clone(),
+        }
+    }
+
+    pub(crate) fn opentelemetry_url(&self) -> Option<String> {
+        self.opentelemetry_url.clone()
+    }
+
+    pub(crate) fn store(&self) -> StoreConfig {
+        match self.store {
+            StoreKind::File => {
+                let mut path = self.path.clone();
+                path.push("files");
+                StoreConfig::File { path }
+            }
+            StoreKind::S3 => self.object_store(),
+        }
+    }
+
+    pub(crate) fn command(&self) -> Option<Command> {
+        self.command.clone()
+    }
+
+    pub(crate) fn store_for(&self, arg: &StoreArg) -> StoreConfig {
+        match arg {
+            StoreArg::Directory(path) => StoreConfig::File { path: path.clone() },
+            StoreArg::S3 => self.object_store(),
+        }
+    }
+
+    fn object_store(&self) -> StoreConfig {
+        StoreConfig::Object {
+            endpoint: self.s3_endpoint.clone().unwrap_or_default(),
+            bucket: self.s3_bucket.clone().unwrap_or_default(),
+            region: self.s3_region.clone(),
+            access_key: self.s3_access_key.clone().unwrap_or_default(),
+            secret_key: self.s3_secret_key.clone().unwrap_or_default(),
+            timeout: Duration::from_secs(self.s3_timeout),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid store supplied, {0}")]
+pub(crate) struct StoreKindError(String);
+
+#[derive(Clone, Debug)]
+enum StoreKind {
+    File,
+    S3,
+}
+
+impl std::str::FromStr for StoreKind {
+    type Err = StoreKindError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(StoreKind::File),
+            "s3" => Ok(StoreKind::S3),
+            other => Err(StoreKindError(other.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid download mode supplied, {0}")]
+pub(crate) struct DownloadModeError(String);
+
+#[derive(Clone, Debug)]
+pub(crate) enum DownloadMode {
+    Open,
+    ApiKey,
+    Disabled,
+}
+
+impl std::str::FromStr for DownloadMode {
+    type Err = DownloadModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(DownloadMode::Open),
+            "api-key" => Ok(DownloadMode::ApiKey),
+            "disabled" => Ok(DownloadMode::Disabled),
+            other => Err(DownloadModeError(other.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid log format supplied, {0}")]
+pub(crate) struct LogFormatError(String);
+
+#[derive(Clone, Debug)]
+pub(crate) enum LogFormat {
+    Normal,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = LogFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(LogFormat::Normal),
+            "json" => Ok(LogFormat::Json),
+            other => Err(LogFormatError(other.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid log rotation supplied, {0}")]
+pub(crate) struct LogRotationError(String);
+
+#[derive(Clone, Debug)]
+pub(crate) enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl std::str::FromStr for LogRotation {
+    type Err = LogRotationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hourly" => Ok(LogRotation::Hourly),
+            "daily" => Ok(LogRotation::Daily),
+            "never" => Ok(LogRotation::Never),
+            other => Err(LogRotationError(other.to_string())),
+        }
+    }
+}
+
+/// Preset names mapped to their transformation segments
+pub(crate) type Presets = HashMap<String, Vec<String>>;
+
+#[derive(Clone, Debug)]
+struct Preset {
+    name: String,
+    segments: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid preset supplied, {0}, expected name=transformation/transformation")]
+pub(crate) struct PresetError(String);
+
+impl std::str::FromStr for Preset {
+    type Err = PresetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut iter = s.splitn(2, '=');
+        let name = iter.next().unwrap_or_default().trim();
+        let chain = iter.next().unwrap_or_default().trim();
+
+        if name.is_empty() || name.contains('/') || chain.is_empty() {
+            return Err(PresetError(s.to_string()));
+        }
+
+        Ok(Preset {
+            name: name.to_string(),
+            segments: chain
+                .split('/')
+                .filter(|segment| !segment.is_empty())
+                .map(|segment| segment.to_string())
+                .collect(),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Dimensions(usize, usize);
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid dimensions supplied, {0}, expected {{width}}x{{height}}")]
+pub(crate) struct DimensionsError(String);
+
+impl std::str::FromStr for Dimensions {
+    type Err = DimensionsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_dimensions(s)
+            .map(|(width, height)| Dimensions(width, height))
+            .ok_or_else(|| DimensionsError(s.to_string()))
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -100,6 +885,15 @@ impl Format {
         }
     }
 
+    /// The name this format is parsed from
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpg",
+            Format::Png => "png",
+            Format::Webp => "webp",
+        }
+    }
+
     pub(crate) fn to_magick_format(&self) -> &'static str {
         match self {
             Format::Jpeg => "JPEG",
@@ -0,0 +1,437 @@
+use crate::error::UploadError;
+use actix_web::{
+    client::Client,
+    http::{header::LOCATION, Uri},
+    web,
+};
+use bytes::Bytes;
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tracing::{debug, instrument};
+
+/// Restrictions on the URLs the server will fetch images from
+#[derive(Clone, Debug)]
+pub(crate) struct DownloadPolicy {
+    pub(crate) allow_private: bool,
+    pub(crate) allowed_domains: Option<HashSet<String>>,
+    pub(crate) denied_domains: HashSet<String>,
+    pub(crate) max_redirects: usize,
+    pub(crate) timeout: Duration,
+    pub(crate) max_concurrent: usize,
+    pub(crate) max_size: usize,
+}
+
+// Downloads in progress across all workers
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+struct InFlight;
+
+impl InFlight {
+    fn acquire(max: usize) -> Option<Self> {
+        // The guard undoes the increment when it's dropped, whether or not there was room
+        let guard = InFlight;
+
+        if IN_FLIGHT.fetch_add(1, Ordering::SeqCst) >= max {
+            return None;
+        }
+
+        Some(guard)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl DownloadPolicy {
+    // Ensure the URL points somewhere we're willing to send a request
+    //
+    // Returns the address the request should connect to, or None if any address is acceptable
+    async fn check(&self, uri: &Uri) -> Result<Option<SocketAddr>, UploadError> {
+        match uri.scheme_str() {
+            Some("http") | Some("https") => (),
+            Some(scheme) => return Err(UploadError::UnsupportedScheme(scheme.to_owned())),
+            None => return Err(UploadError::InvalidUrl(uri.to_string())),
+        }
+
+        let host = uri
+            .host()
+            .ok_or_else(|| UploadError::InvalidUrl(uri.to_string()))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .trim_end_matches('.')
+            .to_lowercase();
+
+        if self.denied_domains.iter().any(|d| matches_domain(&host, d)) {
+            return Err(UploadError::ForbiddenDomain(host));
+        }
+
+        if let Some(allowed) = &self.allowed_domains {
+            if !allowed.iter().any(|d| matches_domain(&host, d)) {
+                return Err(UploadError::ForbiddenDomain(host));
+            }
+        }
+
+        if self.allow_private {
+            return Ok(None);
+        }
+
+        let port = uri
+            .port_u16()
+            .unwrap_or_else(|| if uri.scheme_str() == Some("https") { 443 } else { 80 });
+
+        let addrs = web::block(move || {
+            (host.as_str(), port)
+                .to_socket_addrs()
+                .map(|addrs| addrs.collect::<Vec<_>>())
+        })
+        .await?;
+
+        for addr in &addrs {
+            if !is_public(addr.ip()) {
+                return Err(UploadError::ForbiddenAddress(addr.ip().to_string()));
+            }
+        }
+
+        addrs
+            .into_iter()
+            .next()
+            .map(Some)
+            .ok_or_else(|| UploadError::InvalidUrl(uri.to_string()))
+    }
+}
+
+// A host matches a domain if it is the domain or one of its subdomains
+fn matches_domain(host: &str, domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match embedded_v4(ip) {
+            Some(v4) => is_public_v4(v4),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+// The IPv4 host reached through an IPv6 address, for the ranges that carry one
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let v4 = |high: u16, low: u16| Ipv4Addr::from(u32::from(high) << 16 | u32::from(low));
+
+    // ::/96, IPv4-compatible, and ::ffff:0:0/96, IPv4-mapped
+    if segments[..5].iter().all(|s| *s == 0) && (segments[5] == 0 || segments[5] == 0xffff) {
+        return Some(v4(segments[6], segments[7]));
+    }
+
+    // 64:ff9b::/96, NAT64
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        return Some(v4(segments[6], segments[7]));
+    }
+
+    // 2002::/16, 6to4, which puts the address right after the prefix
+    if segments[0] == 0x2002 {
+        return Some(v4(segments[1], segments[2]));
+    }
+
+    None
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // 0.0.0.0/8
+        || octets[0] == 0
+        // 100.64.0.0/10, shared address space
+        || (octets[0] == 100 && octets[1] & 0b1100_0000 == 64)
+        // 192.0.0.0/24, protocol assignments
+        || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+        // 198.18.0.0/15, benchmarking
+        || (octets[0] == 198 && octets[1] & 0b1111_1110 == 18)
+        // 240.0.0.0/4, reserved, including the broadcast address
+        || octets[0] >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    let first = segments[0];
+
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // 64:ff9b:1::/48, local-use NAT64
+        || segments[..3] == [0x64, 0xff9b, 1]
+        // fc00::/7, unique local
+        || first & 0xfe00 == 0xfc00
+        // fe80::/10, link local
+        || first & 0xffc0 == 0xfe80
+        // fec0::/10, site local
+        || first & 0xffc0 == 0xfec0)
+}
+
+// Resolve a Location header against the URL that returned it
+fn resolve(base: &Uri, location: &str) -> Result<Uri, UploadError> {
+    let invalid = || UploadError::InvalidUrl(location.to_owned());
+
+    let uri: Uri = location.parse().map_err(|_| invalid())?;
+
+    if uri.scheme().is_some() {
+        return Ok(uri);
+    }
+
+    let mut parts = uri.into_parts();
+    parts.scheme = base.scheme().cloned();
+    parts.authority = base.authority().cloned();
+
+    Uri::from_parts(parts).map_err(|_| invalid())
+}
+
+/// Fetch an image, checking the URL and every redirect against the policy
+///
+/// Fails with `UploadError::Busy` when the maximum number of downloads are already running
+#[instrument(skip(client))]
+pub(crate) async fn fetch(
+    client: &Client,
+    policy: &DownloadPolicy,
+    url: &str,
+) -> Result<Bytes, UploadError> {
+    let in_flight = InFlight::acquire(policy.max_concurrent).ok_or_else(|| {
+        debug!("Too many downloads in progress");
+        UploadError::Busy
+    })?;
+
+    let res = match actix_rt::time::timeout(policy.timeout, fetch_inner(client, policy, url)).await
+    {
+        Ok(res) => res,
+        Err(_) => Err(UploadError::DownloadTimeout),
+    };
+
+    drop(in_flight);
+    res
+}
+
+async fn fetch_inner(
+    client: &Client,
+    policy: &DownloadPolicy,
+    url: &str,
+) -> Result<Bytes, UploadError> {
+    let mut uri: Uri = url
+        .parse()
+        .map_err(|_| UploadError::InvalidUrl(url.to_owned()))?;
+
+    // The client doesn't follow redirects itself, so each hop can be checked here
+    for _ in 0..=policy.max_redirects {
+        let addr = policy.check(&uri).await?;
+
+        let mut req = client.get(uri.clone()).timeout(policy.timeout);
+
+        // Connect to the address that was checked rather than letting the client look the host
+        // up again, since a second lookup could give a different answer
+        if let Some(addr) = addr {
+            req = req.address(addr);
+        }
+
+        let mut res = req.send().await?;
+
+        if res.status().is_redirection() {
+            let location = res
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or_else(|| UploadError::Download(res.status()))?;
+
+            debug!("Following redirect to {}", location);
+            uri = resolve(&uri, location)?;
+            continue;
+        }
+
+        if !res.status().is_success() {
+            return Err(UploadError::Download(res.status()));
+        }
+
+        return Ok(res.body().limit(policy.max_size).await?);
+    }
+
+    Err(UploadError::TooManyRedirects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow_private: bool) -> DownloadPolicy {
+        DownloadPolicy {
+            allow_private,
+            allowed_domains: None,
+            denied_domains: HashSet::new(),
+            max_redirects: 5,
+            timeout: Duration::from_secs(30),
+            max_concurrent: 16,
+            max_size: 1024,
+        }
+    }
+
+    fn domains(domains: &[&str]) -> HashSet<String> {
+        domains.iter().map(|d| d.to_string()).collect()
+    }
+
+    async fn check(policy: &DownloadPolicy, url: &str) -> Result<Option<SocketAddr>, UploadError> {
+        policy.check(&url.parse().unwrap()).await
+    }
+
+    fn is_public_str(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn allows_public_addresses() {
+        for ip in &[
+            "93.184.216.34",
+            "1.1.1.1",
+            "2606:2800:220:1:248:1893:25c8:1946",
+            "::ffff:93.184.216.34",
+            "64:ff9b::5db8:d822",
+            "2002:5db8:d822::1",
+        ] {
+            assert!(is_public_str(ip), "{} should be public", ip);
+        }
+    }
+
+    #[test]
+    fn rejects_non_public_addresses() {
+        for ip in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.1.2.3",
+            "100.64.0.1",
+            "192.0.0.1",
+            "192.0.2.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "::ffff:127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::a00:1",
+            "64:ff9b::7f00:1",
+            "64:ff9b:1::1",
+            "2002:a00:1::1",
+            "2002:7f00:1::1",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "fec0::1",
+            "ff02::1",
+        ] {
+            assert!(!is_public_str(ip), "{} should not be public", ip);
+        }
+    }
+
+    #[test]
+    fn matches_subdomains() {
+        assert!(matches_domain("example.com", "example.com"));
+        assert!(matches_domain("cdn.example.com", "example.com"));
+        assert!(matches_domain("a.cdn.example.com", "Example.com."));
+        assert!(!matches_domain("badexample.com", "example.com"));
+        assert!(!matches_domain("example.com.evil", "example.com"));
+        assert!(!matches_domain("com", "example.com"));
+    }
+
+    #[actix_rt::test]
+    async fn rejects_unsupported_schemes() {
+        let res = check(&policy(true), "ftp://example.com/a.png").await;
+        assert!(matches!(res, Err(UploadError::UnsupportedScheme(_))));
+    }
+
+    #[actix_rt::test]
+    async fn rejects_denied_domains() {
+        let policy = DownloadPolicy {
+            denied_domains: domains(&["example.com"]),
+            ..policy(true)
+        };
+
+        for url in &["http://example.com/a.png", "https://CDN.Example.com./a.png"] {
+            let res = check(&policy, url).await;
+            assert!(
+                matches!(res, Err(UploadError::ForbiddenDomain(_))),
+                "{} was allowed",
+                url
+            );
+        }
+        assert!(check(&policy, "http://example.org/a.png").await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn only_allows_listed_domains() {
+        let policy = DownloadPolicy {
+            allowed_domains: Some(domains(&["example.com"])),
+            denied_domains: domains(&["private.example.com"]),
+            ..policy(true)
+        };
+
+        assert!(check(&policy, "http://example.com/a.png").await.is_ok());
+        assert!(check(&policy, "http://cdn.example.com/a.png").await.is_ok());
+
+        // Denials win over the allow-list
+        for url in &[
+            "http://example.org/a.png",
+            "http://private.example.com/a.png",
+        ] {
+            let res = check(&policy, url).await;
+            assert!(
+                matches!(res, Err(UploadError::ForbiddenDomain(_))),
+                "{} was allowed",
+                url
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn pins_checked_addresses() {
+        let policy = policy(false);
+
+        let addr = check(&policy, "https://93.184.216.34/a.png").await.unwrap();
+        assert_eq!(addr, Some("93.184.216.34:443".parse().unwrap()));
+
+        for url in &[
+            "http://127.0.0.1/a.png",
+            "http://[::ffff:10.0.0.1]:8080/a.png",
+            "http://[64:ff9b::a9fe:a9fe]/a.png",
+        ] {
+            let res = check(&policy, url).await;
+            assert!(
+                matches!(res, Err(UploadError::ForbiddenAddress(_))),
+                "{} was allowed",
+                url
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn skips_address_checks_when_private_is_allowed() {
+        let res = check(&policy(true), "http://127.0.0.1/a.png").await;
+        assert!(matches!(res, Ok(None)));
+    }
+}
@@ -1,5 +1,8 @@
 use crate::validate::GifError;
-use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use actix_web::{
+    http::{header::RETRY_AFTER, StatusCode},
+    HttpResponse, ResponseError,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub(crate) enum UploadError {
@@ -12,6 +15,12 @@ pub(crate) enum UploadError {
     #[error("Error in DB, {0}")]
     Db(#[from] sled::Error),
 
+    #[error("Error rendering metrics, {0}")]
+    Metrics(#[from] prometheus::Error),
+
+    #[error("Error serializing details, {0}")]
+    Json(#[from] serde_json::Error),
+
     #[error("Error parsing string, {0}")]
     ParseString(#[from] std::string::FromUtf8Error),
 
@@ -48,6 +57,30 @@ pub(crate) enum UploadError {
     #[error("Unable to send request, {0}")]
     SendRequest(String),
 
+    #[error("Unsupported URL scheme, {0}")]
+    UnsupportedScheme(String),
+
+    #[error("Invalid URL, {0}")]
+    InvalidUrl(String),
+
+    #[error("Downloading from this domain is not allowed, {0}")]
+    ForbiddenDomain(String),
+
+    #[error("Downloading from this address is not allowed, {0}")]
+    ForbiddenAddress(String),
+
+    #[error("Download followed too many redirects")]
+    TooManyRedirects,
+
+    #[error("Download took too long")]
+    DownloadTimeout,
+
+    #[error("Downloading images is disabled")]
+    DownloadDisabled,
+
+    #[error("Request is missing a valid API key")]
+    InvalidApiKey,
+
     #[error("No filename provided in request")]
     MissingFilename,
 
@@ -58,7 +91,10 @@ pub(crate) enum UploadError {
     DuplicateAlias,
 
     #[error("Error validating Gif file, {0}")]
-    Gif(#[from] GifError),
+    Gif(GifError),
+
+    #[error("Image exceeds configured limits, {0}")]
+    ImageTooLarge(String),
 
     #[error("Tried to create file, but file already exists")]
     FileExists,
@@ -68,11 +104,111 @@ pub(crate) enum UploadError {
 
     #[error("Error in MagickWand, {0}")]
     Wand(String),
+
+    #[error("Error in object storage, {0}")]
+    ObjectStore(String),
+
+    #[error("Stored file {0} does not match its recorded hash")]
+    ChecksumMismatch(String),
+
+    #[error("Invalid or disallowed transformation, {0}")]
+    InvalidTransformation(String),
+
+    #[error("Transformation exceeds configured limits, {0}")]
+    TransformationLimit(String),
+
+    #[error("Requested a preset that doesn't exist")]
+    MissingPreset,
+
+    #[error("Request signature is missing or invalid")]
+    InvalidSignature,
+
+    #[error("Request signature has expired")]
+    ExpiredSignature,
+
+    #[error("Error processing image, {0}")]
+    Process(String),
+
+    #[error("Too many images are being processed, try again later")]
+    Busy,
+
+    #[error("Image processing took too long")]
+    Timeout,
+
+    #[error("Not ready, {0}")]
+    NotReady(String),
+
+    #[error("Image processing exceeded resource limits, {0}")]
+    ResourceLimit(String),
+
+    #[error("Image was rejected while decoding, {0}")]
+    Rejected(String),
+
+    #[error("Error in decoding process, {0}")]
+    Sandbox(String),
+}
+
+impl UploadError {
+    /// A stable name for the kind of error, for use in metric labels
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            UploadError::Upload(_) => "Upload",
+            UploadError::Save(_) => "Save",
+            UploadError::Db(_) => "Db",
+            UploadError::Metrics(_) => "Metrics",
+            UploadError::Json(_) => "Json",
+            UploadError::ParseString(_) => "ParseString",
+            UploadError::Io(_) => "Io",
+            UploadError::Canceled => "Canceled",
+            UploadError::NoFiles => "NoFiles",
+            UploadError::MissingExtension => "MissingExtension",
+            UploadError::MissingAlias => "MissingAlias",
+            UploadError::MissingFile => "MissingFile",
+            UploadError::InvalidToken => "InvalidToken",
+            UploadError::UnsupportedFormat => "UnsupportedFormat",
+            UploadError::Download(_) => "Download",
+            UploadError::Payload(_) => "Payload",
+            UploadError::SendRequest(_) => "SendRequest",
+            UploadError::UnsupportedScheme(_) => "UnsupportedScheme",
+            UploadError::InvalidUrl(_) => "InvalidUrl",
+            UploadError::ForbiddenDomain(_) => "ForbiddenDomain",
+            UploadError::ForbiddenAddress(_) => "ForbiddenAddress",
+            UploadError::TooManyRedirects => "TooManyRedirects",
+            UploadError::DownloadTimeout => "DownloadTimeout",
+            UploadError::DownloadDisabled => "DownloadDisabled",
+            UploadError::InvalidApiKey => "InvalidApiKey",
+            UploadError::MissingFilename => "MissingFilename",
+            UploadError::Path => "Path",
+            UploadError::DuplicateAlias => "DuplicateAlias",
+            UploadError::Gif(_) => "Gif",
+            UploadError::ImageTooLarge(_) => "ImageTooLarge",
+            UploadError::FileExists => "FileExists",
+            UploadError::Validate(_) => "Validate",
+            UploadError::Wand(_) => "Wand",
+            UploadError::ObjectStore(_) => "ObjectStore",
+            UploadError::ChecksumMismatch(_) => "ChecksumMismatch",
+            UploadError::InvalidTransformation(_) => "InvalidTransformation",
+            UploadError::TransformationLimit(_) => "TransformationLimit",
+            UploadError::MissingPreset => "MissingPreset",
+            UploadError::InvalidSignature => "InvalidSignature",
+            UploadError::ExpiredSignature => "ExpiredSignature",
+            UploadError::Process(_) => "Process",
+            UploadError::Busy => "Busy",
+            UploadError::Timeout => "Timeout",
+            UploadError::NotReady(_) => "NotReady",
+            UploadError::ResourceLimit(_) => "ResourceLimit",
+            UploadError::Rejected(_) => "Rejected",
+            UploadError::Sandbox(_) => "Sandbox",
+        }
+    }
 }
 
 impl From<actix_web::client::SendRequestError> for UploadError {
     fn from(e: actix_web::client::SendRequestError) -> Self {
-        UploadError::SendRequest(e.to_string())
+        match e {
+            actix_web::client::SendRequestError::Timeout => UploadError::DownloadTimeout,
+            e => UploadError::SendRequest(e.to_string()),
+        }
     }
 }
 
@@ -85,6 +221,15 @@ impl From<sled::transaction::TransactionError<UploadError>> for UploadError {
     }
 }
 
+impl From<GifError> for UploadError {
+    fn from(e: GifError) -> Self {
+        match e {
+            GifError::TooLarge(msg) => UploadError::ImageTooLarge(msg),
+            e => UploadError::Gif(e),
+        }
+    }
+}
+
 impl From<actix_form_data::Error> for UploadError {
     fn from(e: actix_form_data::Error) -> Self {
         UploadError::Upload(e.to_string())
@@ -109,14 +254,67 @@ impl ResponseError for UploadError {
             UploadError::Gif(_)
             | UploadError::DuplicateAlias
             | UploadError::NoFiles
-            | UploadError::Upload(_) => StatusCode::BAD_REQUEST,
-            UploadError::MissingAlias | UploadError::MissingFilename => StatusCode::NOT_FOUND,
-            UploadError::InvalidToken => StatusCode::FORBIDDEN,
+            | UploadError::Upload(_)
+            | UploadError::InvalidTransformation(_)
+            | UploadError::TransformationLimit(_)
+            | UploadError::ImageTooLarge(_)
+            | UploadError::UnsupportedScheme(_)
+            | UploadError::InvalidUrl(_)
+            | UploadError::TooManyRedirects => StatusCode::BAD_REQUEST,
+            UploadError::MissingAlias
+            | UploadError::MissingFilename
+            | UploadError::MissingPreset => StatusCode::NOT_FOUND,
+            UploadError::InvalidToken
+            | UploadError::InvalidSignature
+            | UploadError::ExpiredSignature
+            | UploadError::ForbiddenDomain(_)
+            | UploadError::ForbiddenAddress(_)
+            | UploadError::DownloadDisabled => StatusCode::FORBIDDEN,
+            UploadError::InvalidApiKey => StatusCode::UNAUTHORIZED,
+            UploadError::DownloadTimeout => StatusCode::GATEWAY_TIMEOUT,
+            UploadError::ResourceLimit(_) | UploadError::Rejected(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            UploadError::Busy | UploadError::Timeout | UploadError::NotReady(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code()).json(serde_json::json!({ "msg": self.to_string() }))
+        let mut body = serde_json::json!({ "msg": self.to_string() });
+
+        if let UploadError::InvalidTransformation(segment) = self {
+            body["segment"] = serde_json::Value::String(segment.clone());
+        }
+
+        if let Some(request_id) = crate::middleware::request_id() {
+            body["request_id"] = serde_json::Value::String(request_id);
+        }
+
+        let mut builder = HttpResponse::build(self.status_code());
+
+        if let UploadError::Busy = self {
+            builder.set_header(RETRY_AFTER, "1");
+        }
+
+        builder.json(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds_leave_out_fields() {
+        let kind = UploadError::ForbiddenDomain("example.com".to_owned()).kind();
+        assert_eq!(kind, "ForbiddenDomain");
+
+        let kind = UploadError::Download(StatusCode::NOT_FOUND).kind();
+        assert_eq!(kind, "Download");
+
+        assert_eq!(UploadError::TooManyRedirects.kind(), "TooManyRedirects");
     }
 }
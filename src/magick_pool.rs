@@ -0,0 +1,192 @@
+use crate::error::UploadError;
+use futures::channel::oneshot;
+use once_cell::sync::Lazy;
+use std::{
+    cell::Cell,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, instrument, warn, Span};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+thread_local! {
+    // When the operation running on this thread will be abandoned
+    static DEADLINE: Cell<Option<Instant>> = Cell::new(None);
+}
+
+/// When the operation running on the current pool thread will be abandoned, if any
+///
+/// Work that can be stopped early, like a sandboxed child, should be stopped by then rather than
+/// holding the thread after its request has given up on it
+pub(crate) fn deadline() -> Option<Instant> {
+    DEADLINE.with(|deadline| deadline.get())
+}
+
+/// A fixed set of threads for running ImageMagick, with a bounded queue in front of it
+///
+/// Keeping this work off of the shared blocking pool means a burst of large images can't starve
+/// the database and filesystem operations that also run there
+struct MagickPool {
+    sender: SyncSender<Job>,
+    queued: Arc<AtomicUsize>,
+    timeout: Duration,
+}
+
+static POOL: Lazy<MagickPool> = Lazy::new(|| {
+    MagickPool::new(
+        crate::CONFIG.magick_threads(),
+        crate::CONFIG.magick_queue_size(),
+        crate::CONFIG.magick_timeout(),
+    )
+});
+
+impl MagickPool {
+    fn new(threads: usize, queue_size: usize, timeout: Duration) -> Self {
+        let (sender, receiver) = sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for index in 0..threads.max(1) {
+            let receiver = receiver.clone();
+
+            std::thread::Builder::new()
+                .name(format!("magick-worker-{}", index))
+                .spawn(move || worker(receiver))
+                .expect("Failed to spawn ImageMagick worker thread");
+        }
+
+        MagickPool {
+            sender,
+            queued: Arc::new(AtomicUsize::new(0)),
+            timeout,
+        }
+    }
+
+    #[instrument(skip(self, f), fields(queue_depth))]
+    async fn run<F, T>(&self, f: F) -> Result<T, UploadError>
+    where
+        F: FnOnce() -> Result<T, UploadError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let (started_tx, started_rx) = oneshot::channel();
+        let queued = self.queued.clone();
+        let timeout = self.timeout;
+        let span = Span::current();
+
+        let job: Job = Box::new(move || {
+            queued.fetch_sub(1, Ordering::SeqCst);
+            let entered = span.enter();
+
+            if tx.is_canceled() {
+                debug!("Skipping ImageMagick operation for a request that went away");
+                return;
+            }
+            let deadline = Instant::now() + timeout;
+            let _ = started_tx.send(deadline);
+
+            DEADLINE.with(|cell| cell.set(Some(deadline)));
+            let res = std::panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| {
+                error!("Panic in ImageMagick worker");
+                Err(UploadError::Canceled)
+            });
+            DEADLINE.with(|cell| cell.set(None));
+
+            drop(entered);
+            let _ = tx.send(res);
+        });
+
+        let depth = self.queued.fetch_add(1, Ordering::SeqCst) + 1;
+        Span::current().record("queue_depth", &depth);
+        // Also record it next to the request's status and duration, when there is a request
+        if let Some(span) = crate::middleware::request_span() {
+            span.record("queue_depth", &depth);
+        }
+
+        match self.sender.try_send(job) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                debug!("ImageMagick queue is full");
+                return Err(UploadError::Busy);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                return Err(UploadError::Canceled);
+            }
+        }
+
+        // Time spent waiting in the queue doesn't count towards the timeout
+        let deadline = started_rx.await.map_err(|_| UploadError::Canceled)?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        match actix_rt::time::timeout(remaining, rx).await {
+            Ok(res) => res.map_err(|_| UploadError::Canceled)?,
+            Err(_) => {
+                warn!("ImageMagick operation timed out");
+                Err(UploadError::Timeout)
+            }
+        }
+    }
+}
+
+fn worker(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(e) => e.into_inner().recv(),
+        };
+
+        match job {
+            Ok(job) => job(),
+            // The pool has been dropped
+            Err(_) => break,
+        }
+    }
+}
+
+/// Run an ImageMagick operation on the dedicated pool
+///
+/// Fails with `UploadError::Busy` rather than waiting when the queue is full, and with
+/// `UploadError::Timeout` when the operation doesn't finish in time once a thread picks it up.
+/// Operations whose requests went away while they were queued are skipped.
+///
+/// The timeout only stops the waiting. ImageMagick can't be interrupted inside this process,
+/// since reaching MAGICK_TIME_LIMIT exits the whole server, so an operation that times out keeps
+/// its thread until it finishes. Sandboxed operations check `deadline` and kill their child
+/// instead. Without the sandbox, a run of slow inputs can hold every thread while their requests
+/// report timeouts, and new work queues up behind them or is rejected as busy.
+pub(crate) async fn run<F, T>(f: F) -> Result<T, UploadError>
+where
+    F: FnOnce() -> Result<T, UploadError> + Send + 'static,
+    T: Send + 'static,
+{
+    POOL.run(f).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn times_out_and_recovers() {
+        let pool = MagickPool::new(1, 4, Duration::from_millis(100));
+
+        let res = pool
+            .run(|| {
+                std::thread::sleep(Duration::from_millis(300));
+                Ok(())
+            })
+            .await;
+        assert!(matches!(res, Err(UploadError::Timeout)));
+
+        // The next job waits for the thread to finish the abandoned one, then runs with a deadline
+        let res = pool.run(|| Ok(deadline().is_some())).await;
+        assert!(matches!(res, Ok(true)));
+    }
+}
@@ -1,28 +1,49 @@
 use actix_form_data::{Field, Form, Value};
+use actix_rt::signal::unix::{signal, SignalKind};
 use actix_web::{
     client::Client,
+    dev::Server,
     guard,
-    http::header::{CacheControl, CacheDirective},
-    middleware::{Compress, Logger},
-    web, App, HttpResponse, HttpServer,
+    http::header::{CacheControl, CacheDirective, ACCEPT, VARY},
+    middleware::Compress,
+    web, App, HttpRequest, HttpResponse, HttpServer,
 };
 use futures::stream::{Stream, TryStreamExt};
 use once_cell::sync::Lazy;
-use std::{collections::HashSet, path::PathBuf, sync::Once};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Once,
+    },
+};
 use structopt::StructOpt;
-use tracing::{debug, error, info, instrument, Span};
-use tracing_subscriber::EnvFilter;
+use tracing::{debug, error, info, instrument, warn, Span};
 
+mod concurrent_processor;
 mod config;
+mod download;
 mod error;
+mod magick_pool;
+mod metrics;
 mod middleware;
 mod processor;
+mod sandbox;
+mod signature;
+mod store;
+mod telemetry;
 mod upload_manager;
 mod validate;
 
 use self::{
-    config::Config, error::UploadError, middleware::Tracing, processor::process_image,
-    upload_manager::UploadManager, validate::image_webp,
+    config::{Command, Config, DownloadMode, Format, Presets},
+    download::DownloadPolicy,
+    error::UploadError,
+    middleware::{ApiKey, Tracing},
+    processor::{process_image, Limits, ProcessChain},
+    upload_manager::UploadManager,
+    validate::{image_webp, ptos, ImageLimits},
 };
 
 const MEGABYTES: usize = 1024 * 1024;
@@ -30,42 +51,7 @@ const HOURS: u32 = 60 * 60;
 
 static CONFIG: Lazy<Config> = Lazy::new(|| Config::from_args());
 static MAGICK_INIT: Once = Once::new();
-
-// Try writing to a file
-#[instrument(skip(bytes))]
-async fn safe_save_file(path: PathBuf, bytes: bytes::Bytes) -> Result<(), UploadError> {
-    if let Some(path) = path.parent() {
-        // create the directory for the file
-        debug!("Creating directory {:?}", path);
-        actix_fs::create_dir_all(path.to_owned()).await?;
-    }
-
-    // Only write the file if it doesn't already exist
-    debug!("Checking if {:?} already exists", path);
-    if let Err(e) = actix_fs::metadata(path.clone()).await {
-        if e.kind() != Some(std::io::ErrorKind::NotFound) {
-            return Err(e.into());
-        }
-    } else {
-        return Ok(());
-    }
-
-    // Open the file for writing
-    debug!("Creating {:?}", path);
-    let file = actix_fs::file::create(path.clone()).await?;
-
-    // try writing
-    debug!("Writing to {:?}", path);
-    if let Err(e) = actix_fs::file::write(file, bytes).await {
-        error!("Error writing {:?}, {}", path, e);
-        // remove file if writing failed before completion
-        actix_fs::remove_file(path).await?;
-        return Err(e.into());
-    }
-    debug!("{:?} written", path);
-
-    Ok(())
-}
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
 
 fn to_ext(mime: mime::Mime) -> &'static str {
     if mime == mime::IMAGE_PNG {
@@ -89,10 +75,14 @@ fn from_ext(ext: std::ffi::OsString) -> mime::Mime {
 }
 
 /// Handle responding to succesful uploads
-#[instrument(skip(value, manager))]
+#[instrument(skip(value, manager, whitelist, limits, presets))]
 async fn upload(
     value: Value,
     manager: web::Data<UploadManager>,
+    query: web::Query<VariantsQuery>,
+    whitelist: web::Data<Option<HashSet<String>>>,
+    limits: web::Data<Limits>,
+    presets: web::Data<Presets>,
 ) -> Result<HttpResponse, UploadError> {
     let images = value
         .map()
@@ -109,6 +99,14 @@ async fn upload(
             .and_then(|s| s.to_str())
         {
             info!("Uploaded {} as {:?}", image.filename, saved_as);
+            let chains = eager_chains(
+                query.variants.as_deref(),
+                whitelist.as_ref().as_ref(),
+                &limits,
+                &presets,
+            );
+            manager.generate_variants(saved_as.to_owned(), chains);
+
             let delete_token = manager.delete_token(saved_as.to_owned()).await?;
             files.push(serde_json::json!({
                 "file": saved_as,
@@ -124,23 +122,46 @@ async fn upload(
 }
 
 /// download an image from a URL
-#[instrument(skip(client, manager))]
+#[instrument(skip(req, client, manager, policy, whitelist, limits, presets))]
 async fn download(
+    req: HttpRequest,
     client: web::Data<Client>,
     manager: web::Data<UploadManager>,
+    policy: web::Data<DownloadPolicy>,
     query: web::Query<UrlQuery>,
+    whitelist: web::Data<Option<HashSet<String>>>,
+    limits: web::Data<Limits>,
+    presets: web::Data<Presets>,
 ) -> Result<HttpResponse, UploadError> {
-    let mut res = client.get(&query.url).send().await?;
-
-    if !res.status().is_success() {
-        return Err(UploadError::Download(res.status()));
+    match CONFIG.download_mode() {
+        DownloadMode::Open => (),
+        DownloadMode::ApiKey => {
+            self::signature::verify_api_key(&CONFIG.api_keys(), req.headers())?;
+        }
+        DownloadMode::Disabled => return Err(UploadError::DownloadDisabled),
     }
 
-    let fut = res.body().limit(CONFIG.max_file_size() * MEGABYTES);
+    let bytes = self::download::fetch(&client, &policy, &query.url)
+        .await
+        .map_err(|e| {
+            self::metrics::download_failed(&e);
+            e
+        })?;
 
-    let stream = Box::pin(futures::stream::once(fut));
+    let stream = Box::pin(futures::stream::once(async {
+        Ok(bytes) as Result<_, UploadError>
+    }));
 
     let alias = manager.upload(stream).await?;
+
+    let chains = eager_chains(
+        query.variants.as_deref(),
+        whitelist.as_ref().as_ref(),
+        &limits,
+        &presets,
+    );
+    manager.generate_variants(alias.clone(), chains);
+
     let delete_token = manager.delete_token(alias.clone()).await?;
 
     Ok(HttpResponse::Created().json(serde_json::json!({
@@ -152,8 +173,115 @@ async fn download(
     })))
 }
 
-/// Delete aliases and files
+/// Expose metrics for prometheus to scrape
+#[instrument]
+async fn serve_metrics() -> Result<HttpResponse, UploadError> {
+    let (content_type, body) = self::metrics::render()?;
+
+    Ok(HttpResponse::Ok().content_type(content_type).body(body))
+}
+
+/// Report that the process is running
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "msg": "ok" }))
+}
+
+/// Report whether the server can handle requests
 #[instrument(skip(manager))]
+async fn readyz(manager: web::Data<UploadManager>) -> Result<HttpResponse, UploadError> {
+    if SHUTTING_DOWN.load(Ordering::SeqCst) {
+        return Err(UploadError::NotReady("shutting down".to_owned()));
+    }
+
+    if !MAGICK_INIT.is_completed() {
+        return Err(UploadError::NotReady(
+            "ImageMagick isn't initialized".to_owned(),
+        ));
+    }
+
+    manager.check_ready().await.map_err(|e| {
+        warn!("Readiness check failed, {}", e);
+        UploadError::NotReady(e.to_string())
+    })?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "msg": "ok" })))
+}
+
+// Stop reporting ready when asked to shut down, giving load balancers time to move traffic
+// elsewhere before the servers stop
+async fn shutdown_on_signal(servers: Vec<Server>) -> Result<(), std::io::Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    futures::future::select(
+        Box::pin(actix_rt::signal::ctrl_c()),
+        Box::pin(terminate.recv()),
+    )
+    .await;
+
+    info!(
+        "Shutting down in {} seconds",
+        CONFIG.shutdown_delay().as_secs()
+    );
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    actix_rt::time::delay_for(CONFIG.shutdown_delay()).await;
+
+    for server in servers {
+        server.stop(true).await;
+    }
+
+    Ok(())
+}
+
+async fn log_shutdown_error<F>(fut: F)
+where
+    F: std::future::Future<Output = Result<(), std::io::Error>>,
+{
+    if let Err(e) = fut.await {
+        error!("Couldn't listen for shutdown signals, {}", e);
+    }
+}
+
+// Parse the variants requested for generation at upload time
+//
+// Each entry is either a preset name or a transformation chain. Invalid entries are skipped
+// rather than failing the request, since the upload has already been saved by now
+fn eager_chains(
+    variants: Option<&str>,
+    whitelist: Option<&HashSet<String>>,
+    limits: &Limits,
+    presets: &Presets,
+) -> Vec<ProcessChain> {
+    // Arbitrary chains would sidestep the restrictions on serving them
+    let presets_only = CONFIG.presets_only() || CONFIG.signing_key().is_some();
+
+    variants
+        .into_iter()
+        .flat_map(|variants| variants.split(','))
+        .map(|variant| variant.trim())
+        .filter(|variant| !variant.is_empty())
+        .filter_map(|variant| {
+            let res = match presets.get(variant) {
+                Some(segments) => self::processor::build_chain(segments, None, true, limits),
+                None if presets_only => Err(UploadError::InvalidTransformation(variant.to_owned())),
+                None => {
+                    let segments: Vec<String> = variant.split('/').map(|s| s.to_owned()).collect();
+                    self::processor::build_chain(&segments, whitelist, true, limits)
+                }
+            };
+
+            match res {
+                Ok(chain) => Some(chain),
+                Err(e) => {
+                    warn!("Skipping variant {}, {}", variant, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Delete aliases and files
+#[instrument(skip(manager, path_entries))]
 async fn delete(
     manager: web::Data<UploadManager>,
     path_entries: web::Path<(String, String)>,
@@ -166,97 +294,252 @@ async fn delete(
 }
 
 /// Serve files
-#[instrument(skip(manager, whitelist))]
+#[instrument(skip(req, manager, whitelist, limits, query))]
 async fn serve(
+    req: HttpRequest,
+    segments: web::Path<String>,
+    manager: web::Data<UploadManager>,
+    whitelist: web::Data<Option<HashSet<String>>>,
+    limits: web::Data<Limits>,
+    query: web::Query<SignatureQuery>,
+) -> Result<HttpResponse, UploadError> {
+    let (chain, alias) = chain_from_path(
+        &segments.into_inner(),
+        &query,
+        whitelist.as_ref().as_ref(),
+        &limits,
+    )?;
+
+    serve_chain(req, manager, chain, alias).await
+}
+
+/// Describe an original image or one of its variants
+#[instrument(skip(manager, whitelist, limits, query))]
+async fn details(
     segments: web::Path<String>,
     manager: web::Data<UploadManager>,
     whitelist: web::Data<Option<HashSet<String>>>,
+    limits: web::Data<Limits>,
+    query: web::Query<SignatureQuery>,
 ) -> Result<HttpResponse, UploadError> {
-    let mut segments: Vec<String> = segments
-        .into_inner()
-        .split('/')
-        .map(|s| s.to_string())
-        .collect();
+    let (chain, alias) = chain_from_path(
+        &segments.into_inner(),
+        &query,
+        whitelist.as_ref().as_ref(),
+        &limits,
+    )?;
+
+    let name = manager.from_alias(alias).await?;
+
+    let path = self::processor::build_path(PathBuf::new(), &chain, name.clone());
+    let key = ptos(&path)?;
+
+    let details = if key == name || manager.store().exists(&key).await? {
+        manager.details(&key).await?
+    } else {
+        match generate_variant(manager.clone(), name.clone(), chain, path, key.clone()).await? {
+            Some(bytes) => manager.variant_details(&key, bytes).await?,
+            // The chain leaves the original as it is
+            None => manager.details(&name).await?,
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(details))
+}
+
+// Check a request for a file against the server's restrictions, and split it into the chain to
+// apply and the alias to apply it to
+fn chain_from_path(
+    path: &str,
+    query: &SignatureQuery,
+    whitelist: Option<&HashSet<String>>,
+    limits: &Limits,
+) -> Result<(ProcessChain, String), UploadError> {
+    if let Some(key) = CONFIG.signing_key() {
+        let is_original = !path.contains('/');
+
+        if !(is_original && CONFIG.allow_unsigned_originals()) {
+            debug!("Verifying signature");
+            self::signature::verify(key.as_bytes(), path, query.sig.as_deref(), query.expires)?;
+        }
+    }
+
+    let mut segments: Vec<String> = path.split('/').map(|s| s.to_string()).collect();
     let alias = segments.pop().ok_or(UploadError::MissingFilename)?;
 
+    if CONFIG.presets_only() {
+        if let Some(segment) = segments.first() {
+            return Err(UploadError::InvalidTransformation(segment.clone()));
+        }
+    }
+
     debug!("Building chain");
-    let chain = self::processor::build_chain(&segments, whitelist.as_ref().as_ref());
+    let chain = self::processor::build_chain(
+        &segments,
+        whitelist,
+        CONFIG.strict_transformations(),
+        limits,
+    )?;
     debug!("Chain built");
 
-    let name = manager.from_alias(alias).await?;
-    let base = manager.image_dir();
-    let path = self::processor::build_path(base, &chain, name.clone());
+    Ok((chain, alias))
+}
+
+/// Serve files transformed by a preset
+#[instrument(skip(req, manager, presets, limits))]
+async fn serve_preset(
+    req: HttpRequest,
+    path_entries: web::Path<(String, String)>,
+    manager: web::Data<UploadManager>,
+    presets: web::Data<Presets>,
+    limits: web::Data<Limits>,
+) -> Result<HttpResponse, UploadError> {
+    let (preset, alias) = path_entries.into_inner();
 
-    let ext = path
-        .extension()
-        .ok_or(UploadError::MissingExtension)?
-        .to_owned();
-    let ext = from_ext(ext);
+    let segments = presets.get(&preset).ok_or(UploadError::MissingPreset)?;
 
-    // If the thumbnail doesn't exist, we need to create it
-    if let Err(e) = actix_fs::metadata(path.clone()).await {
-        if e.kind() != Some(std::io::ErrorKind::NotFound) {
-            error!("Error looking up processed image, {}", e);
-            return Err(e.into());
-        }
+    // Presets come from the server's configuration, so they aren't subject to the whitelist
+    debug!("Building chain");
+    let chain = self::processor::build_chain(segments, None, true, &limits)?;
+    debug!("Chain built");
 
-        let mut original_path = manager.image_dir();
-        original_path.push(name.clone());
+    serve_chain(req, manager, chain, alias).await
+}
 
-        // apply chain to the provided image
-        let img_bytes = match process_image(original_path.clone(), chain).await? {
-            Some(bytes) => bytes,
-            None => {
-                let stream = actix_fs::read_to_stream(original_path).await?;
+async fn serve_chain(
+    req: HttpRequest,
+    manager: web::Data<UploadManager>,
+    mut chain: ProcessChain,
+    alias: String,
+) -> Result<HttpResponse, UploadError> {
+    let name = manager.from_alias(alias).await?;
 
-                return Ok(srv_response(stream, ext));
-            }
-        };
+    // The response only depends on the Accept header when the client leaves the format to us
+    let vary = CONFIG.negotiate_format() && chain.output_format().is_none();
+    if vary && accepts_webp(&req) && is_negotiable(&name) {
+        debug!("Client accepts webp, converting");
+        chain.push_format(Format::Webp);
+    }
 
-        let path2 = path.clone();
-        let img_bytes2 = img_bytes.clone();
+    let path = self::processor::build_path(PathBuf::new(), &chain, name.clone());
+    let key = ptos(&path)?;
 
-        // Save the file in another task, we want to return the thumbnail now
-        debug!("Spawning storage task");
-        let span = Span::current();
-        actix_rt::spawn(async move {
-            let entered = span.enter();
-            if let Err(e) = manager.store_variant(path2.clone()).await {
-                error!("Error storing variant, {}", e);
-                return;
-            }
+    let ext = match chain.output_format() {
+        Some(format) => format.to_mime(),
+        None => from_ext(
+            path.extension()
+                .ok_or(UploadError::MissingExtension)?
+                .to_owned(),
+        ),
+    };
 
-            if let Err(e) = safe_save_file(path2, img_bytes2).await {
-                error!("Error saving file, {}", e);
-            }
-            drop(entered);
-        });
+    // If the thumbnail doesn't exist, we need to create it
+    let exists = manager.store().exists(&key).await?;
+    self::metrics::variant_cache(exists);
+
+    if !exists {
+        let img_bytes =
+            match generate_variant(manager.clone(), name.clone(), chain, path, key).await? {
+                Some(bytes) => bytes,
+                None => {
+                    let stream = manager.store().to_stream(&name).await?;
+
+                    return Ok(srv_response(stream, ext, vary));
+                }
+            };
 
         return Ok(srv_response(
             Box::pin(futures::stream::once(async {
                 Ok(img_bytes) as Result<_, UploadError>
             })),
             ext,
+            vary,
         ));
     }
 
-    let stream = actix_fs::read_to_stream(path).await?;
+    let stream = manager.store().to_stream(&key).await?;
+
+    Ok(srv_response(stream, ext, vary))
+}
+
+// Apply a chain to an original, sharing the work with concurrent requests
+//
+// Returns None when the chain doesn't change the image
+async fn generate_variant(
+    manager: web::Data<UploadManager>,
+    name: String,
+    chain: ProcessChain,
+    path: PathBuf,
+    key: String,
+) -> Result<Option<bytes::Bytes>, UploadError> {
+    let manager2 = manager.clone();
+    let span = Span::current();
+
+    self::concurrent_processor::process(
+        key,
+        || async {
+            let original = self::store::to_local_file(manager.store(), &name).await?;
+            process_image(original.path(), chain).await
+        },
+        // Save the file in another task, we want to return the result now
+        move |img_bytes| async move {
+            let entered = span.enter();
+            if let Err(e) = manager2.save_variant(path, img_bytes).await {
+                error!("Error saving variant, {}", e);
+            }
+            drop(entered);
+        },
+    )
+    .await
+}
+
+// Whether the client explicitly listed webp in its Accept header
+fn accepts_webp(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all(ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|range| {
+            let mut parts = range.split(';').map(|part| part.trim());
+
+            parts.next() == Some("image/webp")
+                && parts.all(|param| {
+                    !param.starts_with("q=") || param[2..].parse::<f32>().ok() != Some(0.0)
+                })
+        })
+}
 
-    Ok(srv_response(stream, ext))
+// Animations would be flattened by the conversion, and webp originals are already as small as
+// they're going to get
+fn is_negotiable(name: &str) -> bool {
+    match std::path::Path::new(name).extension() {
+        Some(ext) => {
+            let mime = from_ext(ext.to_owned());
+            mime != mime::IMAGE_GIF && mime != image_webp()
+        }
+        None => false,
+    }
 }
 
 // A helper method to produce responses with proper cache headers
-fn srv_response<S, E>(stream: S, ext: mime::Mime) -> HttpResponse
+fn srv_response<S, E>(stream: S, ext: mime::Mime, vary_accept: bool) -> HttpResponse
 where
     S: Stream<Item = Result<bytes::Bytes, E>> + Unpin + 'static,
     E: Into<UploadError>,
 {
-    HttpResponse::Ok()
-        .set(CacheControl(vec![
-            CacheDirective::Public,
-            CacheDirective::MaxAge(24 * HOURS),
-            CacheDirective::Extension("immutable".to_owned(), None),
-        ]))
+    let mut builder = HttpResponse::Ok();
+
+    builder.set(CacheControl(vec![
+        CacheDirective::Public,
+        CacheDirective::MaxAge(24 * HOURS),
+        CacheDirective::Extension("immutable".to_owned(), None),
+    ]));
+
+    if vary_accept {
+        builder.set_header(VARY, "Accept");
+    }
+
+    builder
         .content_type(ext.to_string())
         .streaming(stream.err_into())
 }
@@ -264,23 +547,113 @@ where
 #[derive(Debug, serde::Deserialize)]
 struct UrlQuery {
     url: String,
+    variants: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct VariantsQuery {
+    variants: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SignatureQuery {
+    sig: Option<String>,
+    expires: Option<u64>,
 }
 
 #[actix_rt::main]
 async fn main() -> Result<(), anyhow::Error> {
     MAGICK_INIT.call_once(|| {
+        // Sandboxed children inherit the limits the server set
+        if !CONFIG.command().map(|c| c.is_sandbox()).unwrap_or(false) {
+            for (key, value) in CONFIG.magick_limits() {
+                std::env::set_var(key, value);
+            }
+        }
+
         magick_rust::magick_wand_genesis();
     });
 
+    // Children write their results to stdout, so they must not log there
+    match CONFIG.command() {
+        Some(Command::SandboxValidate {
+            format,
+            max_width,
+            max_height,
+            max_area,
+            max_frame_count,
+        }) => {
+            let limits = ImageLimits {
+                max_width,
+                max_height,
+                max_area,
+                max_frame_count,
+            };
+            self::sandbox::exit(self::sandbox::run_validate(format, limits));
+        }
+        Some(Command::SandboxProcess { segments }) => {
+            self::sandbox::exit(self::sandbox::run_process(segments));
+        }
+        Some(Command::SandboxInfo) => {
+            self::sandbox::exit(self::sandbox::run_info());
+        }
+        _ => (),
+    }
+
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "info");
     }
 
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
+    // Held so buffered log lines are written out before exiting
+    let _log_guard = self::telemetry::init(CONFIG.log_config(), CONFIG.opentelemetry_url())?;
+
+    if let Some(Command::MigrateStore { from, to }) = CONFIG.command() {
+        let from_store = self::store::build(CONFIG.store_for(&from)).await?;
+        let to_store = self::store::build(CONFIG.store_for(&to)).await?;
+
+        let manager = UploadManager::new(
+            CONFIG.data_dir(),
+            CONFIG.format(),
+            CONFIG.image_limits(),
+            CONFIG.delete_token_length(),
+            from_store,
+        )
+        .await?;
+        manager.migrate_store(&*to_store, to.to_string()).await?;
+
+        return Ok(());
+    }
+
+    if let DownloadMode::ApiKey = CONFIG.download_mode() {
+        if CONFIG.api_keys().is_empty() {
+            return Err(anyhow::anyhow!(
+                "An API key must be set to require one for downloads"
+            ));
+        }
+    }
+
+    if CONFIG.api_keys().is_empty() {
+        warn!("No API keys are set, anyone who can reach /import can use it");
+    }
 
-    let manager = UploadManager::new(CONFIG.data_dir(), CONFIG.format()).await?;
+    // Catch typos in presets now, rather than on the first request for them
+    let presets = CONFIG.presets();
+    let limits = CONFIG.processor_limits();
+    for (name, segments) in presets.iter() {
+        self::processor::build_chain(segments, None, true, &limits)
+            .map_err(|e| anyhow::anyhow!("Invalid preset {}, {}", name, e))?;
+    }
+
+    let store = self::store::build(CONFIG.store()).await?;
+    let manager = UploadManager::new(
+        CONFIG.data_dir(),
+        CONFIG.format(),
+        CONFIG.image_limits(),
+        CONFIG.delete_token_length(),
+        store,
+    )
+    .await?;
+    manager.seed_metrics().await?;
 
     // Create a new Multipart Form validator
     //
@@ -342,18 +715,54 @@ async fn main() -> Result<(), anyhow::Error> {
             })),
         );
 
-    HttpServer::new(move || {
+    // Internal endpoints, served on the public listener unless they have their own
+    let internal = move |cfg: &mut web::ServiceConfig| {
+        cfg.service(
+            web::resource("/import")
+                .wrap(import_form.clone())
+                .wrap(ApiKey::new(CONFIG.api_keys()))
+                .route(web::post().to(upload)),
+        )
+        .service(
+            web::resource("/metrics")
+                .wrap(ApiKey::new(CONFIG.api_keys()))
+                .route(web::get().to(serve_metrics)),
+        );
+    };
+
+    // Probes for the orchestrator, served on every listener
+    let probes = |cfg: &mut web::ServiceConfig| {
+        cfg.service(web::resource("/healthz").route(web::get().to(healthz)))
+            .service(web::resource("/readyz").route(web::get().to(readyz)));
+    };
+
+    let internal_address = CONFIG.internal_address();
+    let manager2 = manager.clone();
+    let limits2 = limits.clone();
+    let presets2 = presets.clone();
+    let internal2 = internal.clone();
+
+    let server = HttpServer::new(move || {
         let client = Client::build()
             .header("User-Agent", "pict-rs v0.1.0-master")
             .finish();
 
+        // Link previews and prefetchers follow GET links, so deleting that way can be turned off
+        let mut delete_resource =
+            web::resource("/delete/{delete_token}/{filename}").route(web::delete().to(delete));
+        if CONFIG.allow_get_delete() {
+            delete_resource = delete_resource.route(web::get().to(delete));
+        }
+
         App::new()
             .wrap(Compress::default())
-            .wrap(Logger::default())
             .wrap(Tracing)
             .data(manager.clone())
             .data(client)
             .data(CONFIG.filter_whitelist())
+            .data(CONFIG.download_policy())
+            .data(limits.clone())
+            .data(presets.clone())
             .service(
                 web::scope("/image")
                     .service(
@@ -364,21 +773,56 @@ async fn main() -> Result<(), anyhow::Error> {
                     )
                     .service(web::resource("/download").route(web::get().to(download)))
                     .service(
-                        web::resource("/delete/{delete_token}/{filename}")
-                            .route(web::delete().to(delete))
-                            .route(web::get().to(delete)),
+                        web::resource("/preset/{preset}/{filename}")
+                            .route(web::get().to(serve_preset)),
                     )
+                    .service(delete_resource)
+                    .service(web::resource("/details/{tail:.*}").route(web::get().to(details)))
                     .service(web::resource("/{tail:.*}").route(web::get().to(serve))),
             )
-            .service(
-                web::resource("/import")
-                    .wrap(import_form.clone())
-                    .route(web::post().to(upload)),
-            )
+            .configure(probes)
+            .configure(|cfg| {
+                if internal_address.is_none() {
+                    internal2(cfg);
+                }
+            })
     })
+    .disable_signals()
     .bind(CONFIG.bind_address())?
-    .run()
-    .await?;
+    .run();
+
+    let internal_address = match internal_address {
+        Some(address) => address,
+        None => {
+            actix_rt::spawn(log_shutdown_error(shutdown_on_signal(vec![server.clone()])));
+            server.await?;
+            self::telemetry::shutdown();
+            return Ok(());
+        }
+    };
+
+    let internal_server = HttpServer::new(move || {
+        App::new()
+            .wrap(Compress::default())
+            .wrap(Tracing)
+            .data(manager2.clone())
+            .data(CONFIG.filter_whitelist())
+            .data(limits2.clone())
+            .data(presets2.clone())
+            .configure(probes)
+            .configure(internal.clone())
+    })
+    .disable_signals()
+    .bind(internal_address)?
+    .run();
+
+    actix_rt::spawn(log_shutdown_error(shutdown_on_signal(vec![
+        server.clone(),
+        internal_server.clone(),
+    ])));
+
+    futures::future::try_join(server, internal_server).await?;
+    self::telemetry::shutdown();
 
     Ok(())
 }
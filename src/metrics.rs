@@ -0,0 +1,190 @@
+use crate::error::UploadError;
+use once_cell::sync::Lazy;
+use prometheus::{
+    core::Collector, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use std::time::Instant;
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+static UPLOADS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("pictrs_uploads_total", "Uploaded images, by outcome and format"),
+            &["outcome", "format"],
+        )
+        .unwrap(),
+    )
+});
+
+static BYTES_STORED: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new(
+            "pictrs_stored_bytes_total",
+            "Bytes written to the store for new originals and variants",
+        )
+        .unwrap(),
+    )
+});
+
+static DUPLICATES: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new(
+            "pictrs_duplicate_uploads_total",
+            "Uploads whose contents were already stored",
+        )
+        .unwrap(),
+    )
+});
+
+static VARIANT_CACHE: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "pictrs_variant_cache_total",
+                "Requests for transformed images, by whether the variant was already stored",
+            ),
+            &["result"],
+        )
+        .unwrap(),
+    )
+});
+
+static PROCESS_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register(
+        Histogram::with_opts(HistogramOpts::new(
+            "pictrs_process_duration_seconds",
+            "Time spent applying transformations",
+        ))
+        .unwrap(),
+    )
+});
+
+static VALIDATE_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register(
+        Histogram::with_opts(HistogramOpts::new(
+            "pictrs_validate_duration_seconds",
+            "Time spent validating uploads",
+        ))
+        .unwrap(),
+    )
+});
+
+static DOWNLOAD_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "pictrs_download_failures_total",
+                "Failed downloads from the download endpoint, by error",
+            ),
+            &["error"],
+        )
+        .unwrap(),
+    )
+});
+
+static STORED_ORIGINALS: Lazy<IntGauge> = Lazy::new(|| {
+    register(IntGauge::new("pictrs_stored_originals", "Original images currently stored").unwrap())
+});
+
+static STORE_SIZE: Lazy<IntGauge> = Lazy::new(|| {
+    register(
+        IntGauge::new(
+            "pictrs_store_size_bytes",
+            "Bytes currently held in the store by originals and variants with recorded details",
+        )
+        .unwrap(),
+    )
+});
+
+fn register<T>(metric: T) -> T
+where
+    T: Collector + Clone + 'static,
+{
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("Metric registered twice");
+    metric
+}
+
+/// Count an upload, labelled with the content type it was stored as
+pub(crate) fn upload(res: Result<&mime::Mime, &UploadError>) {
+    let (outcome, format) = match res {
+        Ok(content_type) => ("success", format_label(content_type)),
+        Err(_) => ("error", "unknown"),
+    };
+
+    UPLOADS.with_label_values(&[outcome, format]).inc();
+}
+
+// Imports can skip validation, so unexpected content types share a label
+fn format_label(content_type: &mime::Mime) -> &'static str {
+    if content_type.type_() != mime::IMAGE {
+        return "other";
+    }
+
+    match content_type.subtype().as_str() {
+        "jpeg" => "jpeg",
+        "png" => "png",
+        "gif" => "gif",
+        "webp" => "webp",
+        _ => "other",
+    }
+}
+
+pub(crate) fn bytes_stored(bytes: u64) {
+    BYTES_STORED.inc_by(bytes as i64);
+}
+
+/// Set the stored totals, from what was already stored at startup
+pub(crate) fn set_stored(originals: usize, bytes: u64) {
+    STORED_ORIGINALS.set(originals as i64);
+    STORE_SIZE.set(bytes as i64);
+}
+
+pub(crate) fn original_stored() {
+    STORED_ORIGINALS.inc();
+}
+
+pub(crate) fn original_removed() {
+    STORED_ORIGINALS.dec();
+}
+
+pub(crate) fn details_stored(size: u64) {
+    STORE_SIZE.add(size as i64);
+}
+
+pub(crate) fn details_removed(size: u64) {
+    STORE_SIZE.sub(size as i64);
+}
+
+pub(crate) fn duplicate() {
+    DUPLICATES.inc();
+}
+
+pub(crate) fn variant_cache(hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    VARIANT_CACHE.with_label_values(&[result]).inc();
+}
+
+pub(crate) fn processed(start: Instant) {
+    PROCESS_DURATION.observe(start.elapsed().as_secs_f64());
+}
+
+pub(crate) fn validated(start: Instant) {
+    VALIDATE_DURATION.observe(start.elapsed().as_secs_f64());
+}
+
+pub(crate) fn download_failed(e: &UploadError) {
+    DOWNLOAD_FAILURES.with_label_values(&[e.kind()]).inc();
+}
+
+/// Render every metric in the prometheus text format
+pub(crate) fn render() -> Result<(String, Vec<u8>), UploadError> {
+    let encoder = TextEncoder::new();
+    let mut buf = Vec::new();
+    encoder.encode(&REGISTRY.gather(), &mut buf)?;
+
+    Ok((encoder.format_type().to_owned(), buf))
+}
@@ -1,18 +1,93 @@
-use actix_web::dev::{Service, Transform};
-use futures::future::{ok, Ready};
-use std::task::{Context, Poll};
+use crate::signature::verify_api_key;
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    Error, HttpRequest, HttpResponse,
+};
+use futures::future::{err, ok, Either, Ready};
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+    time::Instant,
+};
+use tracing::{field::Empty, info, Span};
 use tracing_futures::{Instrument, Instrumented};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use uuid::Uuid;
 
+const REQUEST_ID: &str = "x-request-id";
+
+thread_local! {
+    // The id and span of the request being polled on this thread, for error responses to include
+    // and for work done on the request's behalf to record fields on
+    static CURRENT_REQUEST: RefCell<Option<(Rc<str>, Span)>> = RefCell::new(None);
+}
+
+/// The id of the request currently being handled, if any
+pub(crate) fn request_id() -> Option<String> {
+    CURRENT_REQUEST.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .map(|(request_id, _)| request_id.to_string())
+    })
+}
+
+/// The span of the request currently being handled, if any
+///
+/// Unlike `Span::current()`, this is the top-level request span rather than whichever function
+/// span is innermost
+pub(crate) fn request_span() -> Option<Span> {
+    CURRENT_REQUEST.with(|current| current.borrow().as_ref().map(|(_, span)| span.clone()))
+}
+
+fn with_request<F, T>(request_id: &Rc<str>, span: &Span, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let prev =
+        CURRENT_REQUEST.with(|current| current.replace(Some((request_id.clone(), span.clone()))));
+    let res = f();
+    CURRENT_REQUEST.with(|current| *current.borrow_mut() = prev);
+    res
+}
+
+// Reuse the id our caller sent, so the request can be followed across services
+fn incoming_request_id(req: &ServiceRequest) -> Option<Rc<str>> {
+    let id = req.headers().get(REQUEST_ID)?.to_str().ok()?;
+
+    if id.is_empty() || id.len() > 128 || !id.chars().all(|c| c.is_ascii_graphic()) {
+        return None;
+    }
+
+    Some(Rc::from(id))
+}
+
+// Delete tokens are as good as credentials, so they're kept out of the logs
+fn redact(path: &str) -> String {
+    const DELETE: &str = "/image/delete/";
+
+    match path.strip_prefix(DELETE) {
+        Some(rest) => match rest.find('/') {
+            Some(index) => format!("{}[redacted]{}", DELETE, &rest[index..]),
+            None => format!("{}[redacted]", DELETE),
+        },
+        None => path.to_owned(),
+    }
+}
+
 pub(crate) struct Tracing;
 
 pub(crate) struct TracingMiddleware<S> {
     inner: S,
 }
 
-impl<S> Transform<S> for Tracing
+impl<S, B> Transform<S> for Tracing
 where
-    S: Service,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
     S::Future: 'static,
 {
     type Request = S::Request;
@@ -27,25 +102,164 @@ where
     }
 }
 
-impl<S> Service for TracingMiddleware<S>
+impl<S, B> Service for TracingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = TracingFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: S::Request) -> Self::Future {
+        let request_id =
+            incoming_request_id(&req).unwrap_or_else(|| Rc::from(Uuid::new_v4().to_string()));
+
+        let peer = req
+            .peer_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_default();
+
+        let span = tracing::info_span!(
+            "request",
+            request_id = %request_id,
+            method = %req.method(),
+            path = %redact(req.path()),
+            %peer,
+            queue_depth = Empty
+        );
+        span.set_parent(crate::telemetry::parent_context(req.headers()));
+
+        let request = req.request().clone();
+        let inner = with_request(&request_id, &span, || self.inner.call(req));
+
+        TracingFuture {
+            inner: Box::pin(inner.instrument(span.clone())),
+            request_id,
+            span,
+            request: Some(request),
+            start: Instant::now(),
+        }
+    }
+}
+
+/// Runs a request with its id available to error responses, then adds the id to the response
+pub(crate) struct TracingFuture<F> {
+    inner: Pin<Box<Instrumented<F>>>,
+    request_id: Rc<str>,
+    span: Span,
+    request: Option<HttpRequest>,
+    start: Instant,
+}
+
+impl<F, B> Future for TracingFuture<F>
+where
+    F: Future<Output = Result<ServiceResponse<B>, Error>>,
+{
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let request_id = &this.request_id;
+        let span = &this.span;
+        let inner = &mut this.inner;
+
+        let mut res = match with_request(request_id, span, || inner.as_mut().poll(cx)) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Ok(res)) => res,
+            // Errors would otherwise be rendered after the request id is gone
+            Poll::Ready(Err(e)) => {
+                let request = this.request.take().expect("Polled after completion");
+                let response: HttpResponse = with_request(request_id, span, || e.into());
+                ServiceResponse::new(request, response.into_body())
+            }
+        };
+
+        let entered = this.span.enter();
+        info!(
+            status = res.status().as_u16(),
+            "{} in {:?}",
+            res.status(),
+            this.start.elapsed()
+        );
+        drop(entered);
+
+        if let Ok(value) = HeaderValue::from_str(&this.request_id) {
+            res.headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID), value);
+        }
+
+        Poll::Ready(Ok(res))
+    }
+}
+
+/// Reject requests that don't carry one of the configured API keys
+///
+/// Requests pass through unchecked when no keys are configured
+pub(crate) struct ApiKey {
+    keys: Rc<Vec<String>>,
+}
+
+pub(crate) struct ApiKeyMiddleware<S> {
+    keys: Rc<Vec<String>>,
+    inner: S,
+}
+
+impl ApiKey {
+    pub(crate) fn new(keys: Vec<String>) -> Self {
+        ApiKey {
+            keys: Rc::new(keys),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for ApiKey
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type InitError = ();
+    type Transform = ApiKeyMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ApiKeyMiddleware {
+            keys: self.keys.clone(),
+            inner: service,
+        })
+    }
+}
+
+impl<S, B> Service for ApiKeyMiddleware<S>
 where
-    S: Service,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
     S::Future: 'static,
 {
     type Request = S::Request;
     type Response = S::Response;
     type Error = S::Error;
-    type Future = Instrumented<S::Future>;
+    type Future = Either<S::Future, Ready<Result<S::Response, S::Error>>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         self.inner.poll_ready(cx)
     }
 
     fn call(&mut self, req: S::Request) -> Self::Future {
-        let uuid = Uuid::new_v4();
+        if self.keys.is_empty() {
+            return Either::Left(self.inner.call(req));
+        }
 
-        self.inner
-            .call(req)
-            .instrument(tracing::info_span!("request", ?uuid))
+        match verify_api_key(&self.keys, req.headers()) {
+            Ok(()) => Either::Left(self.inner.call(req)),
+            Err(e) => Either::Right(err(e.into())),
+        }
     }
 }
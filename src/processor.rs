@@ -1,12 +1,13 @@
 use crate::{
+    config::Format,
     error::UploadError,
+    magick_pool, metrics, sandbox,
     validate::{ptos, Op},
 };
-use actix_web::web;
 use bytes::Bytes;
 use magick_rust::MagickWand;
-use std::{collections::HashSet, path::PathBuf};
-use tracing::{debug, instrument, Span};
+use std::{collections::HashSet, path::PathBuf, time::Instant};
+use tracing::{debug, instrument};
 
 pub(crate) trait Processor {
     fn name() -> &'static str
@@ -24,6 +25,16 @@ pub(crate) trait Processor {
     fn path(&self, path: PathBuf) -> PathBuf;
     fn process(&self, wand: &mut MagickWand) -> Result<bool, UploadError>;
 
+    /// The format the processed image should be encoded as, if this processor changes it
+    fn output_format(&self) -> Option<Format> {
+        None
+    }
+
+    /// Ensure the processor's parameters are within the configured limits
+    fn check(&self, _: &Limits) -> Result<(), UploadError> {
+        Ok(())
+    }
+
     fn is_whitelisted(whitelist: Option<&HashSet<String>>) -> bool
     where
         Self: Sized,
@@ -34,6 +45,47 @@ pub(crate) trait Processor {
     }
 }
 
+/// Bounds on the parameters accepted in transformation chains
+#[derive(Clone, Debug)]
+pub(crate) struct Limits {
+    pub(crate) max_thumbnail_size: usize,
+    pub(crate) thumbnail_sizes: Option<HashSet<usize>>,
+    pub(crate) max_dimension: usize,
+    pub(crate) dimensions: Option<HashSet<(usize, usize)>>,
+    pub(crate) max_blur_sigma: f64,
+    pub(crate) blur_sigmas: Option<Vec<f64>>,
+    pub(crate) max_chain_length: usize,
+}
+
+fn limit_err(msg: String) -> UploadError {
+    UploadError::TransformationLimit(msg)
+}
+
+fn check_dimensions(
+    name: &str,
+    width: usize,
+    height: usize,
+    limits: &Limits,
+) -> Result<(), UploadError> {
+    if width > limits.max_dimension || height > limits.max_dimension {
+        return Err(limit_err(format!(
+            "{} {}x{} is larger than {}",
+            name, width, height, limits.max_dimension
+        )));
+    }
+
+    if let Some(dimensions) = &limits.dimensions {
+        if !dimensions.contains(&(width, height)) {
+            return Err(limit_err(format!(
+                "{} {}x{} is not allowed",
+                name, width, height
+            )));
+        }
+    }
+
+    Ok(())
+}
+
 pub(crate) struct Identity;
 
 impl Processor for Identity {
@@ -99,6 +151,26 @@ impl Processor for Thumbnail {
         path
     }
 
+    fn check(&self, limits: &Limits) -> Result<(), UploadError> {
+        if self.0 > limits.max_thumbnail_size {
+            return Err(limit_err(format!(
+                "thumbnail size {} is larger than {}",
+                self.0, limits.max_thumbnail_size
+            )));
+        }
+
+        if let Some(sizes) = &limits.thumbnail_sizes {
+            if !sizes.contains(&self.0) {
+                return Err(limit_err(format!(
+                    "thumbnail size {} is not allowed",
+                    self.0
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
     fn process(&self, wand: &mut MagickWand) -> Result<bool, UploadError> {
         debug!("Thumbnail");
         let width = wand.get_image_width();
@@ -149,6 +221,28 @@ impl Processor for Blur {
         path
     }
 
+    fn check(&self, limits: &Limits) -> Result<(), UploadError> {
+        // NaN fails every comparison, so it has to be ruled out before the upper bound
+        if !self.0.is_finite() || self.0 < 0.0 {
+            return Err(limit_err(format!("blur sigma {} is invalid", self.0)));
+        }
+
+        if self.0 > limits.max_blur_sigma {
+            return Err(limit_err(format!(
+                "blur sigma {} is larger than {}",
+                self.0, limits.max_blur_sigma
+            )));
+        }
+
+        if let Some(sigmas) = &limits.blur_sigmas {
+            if !sigmas.contains(&self.0) {
+                return Err(limit_err(format!("blur sigma {} is not allowed", self.0)));
+            }
+        }
+
+        Ok(())
+    }
+
     fn process(&self, wand: &mut MagickWand) -> Result<bool, UploadError> {
         debug!("Blur");
         if self.0 > 0.0 {
@@ -160,6 +254,396 @@ impl Processor for Blur {
     }
 }
 
+pub(crate) struct Resize(usize, usize);
+
+impl Processor for Resize {
+    fn name() -> &'static str
+    where
+        Self: Sized,
+    {
+        "resize"
+    }
+
+    fn is_processor(s: &str) -> bool
+    where
+        Self: Sized,
+    {
+        s.starts_with(Self::name())
+    }
+
+    fn parse(s: &str) -> Option<Box<dyn Processor + Send>>
+    where
+        Self: Sized,
+    {
+        let (width, height) = parse_dimensions(s.trim_start_matches(Self::name()))?;
+        Some(Box::new(Resize(width, height)))
+    }
+
+    fn path(&self, mut path: PathBuf) -> PathBuf {
+        path.push(Self::name());
+        path.push(format!("{}x{}", self.0, self.1));
+        path
+    }
+
+    fn check(&self, limits: &Limits) -> Result<(), UploadError> {
+        check_dimensions(Self::name(), self.0, self.1, limits)
+    }
+
+    fn process(&self, wand: &mut MagickWand) -> Result<bool, UploadError> {
+        debug!("Resize");
+        let width = wand.get_image_width();
+        let height = wand.get_image_height();
+
+        if width > self.0 || height > self.1 {
+            let ratio = f64::min(self.0 as f64 / width as f64, self.1 as f64 / height as f64);
+
+            let new_width = (width as f64 * ratio).round().max(1.0);
+            let new_height = (height as f64 * ratio).round().max(1.0);
+
+            wand.op(|w| w.sample_image(new_width as usize, new_height as usize))?;
+            Ok(true)
+        } else if wand.op(|w| w.get_image_format())? == "GIF" {
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Gravity {
+    Center,
+    North,
+    Entropy,
+}
+
+impl Gravity {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Gravity::Center => "center",
+            Gravity::North => "north",
+            Gravity::Entropy => "entropy",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "center" => Some(Gravity::Center),
+            "north" => Some(Gravity::North),
+            "entropy" => Some(Gravity::Entropy),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) struct Crop(usize, usize, Gravity);
+
+impl Processor for Crop {
+    fn name() -> &'static str
+    where
+        Self: Sized,
+    {
+        "crop"
+    }
+
+    fn is_processor(s: &str) -> bool
+    where
+        Self: Sized,
+    {
+        s.starts_with(Self::name())
+    }
+
+    fn parse(s: &str) -> Option<Box<dyn Processor + Send>>
+    where
+        Self: Sized,
+    {
+        let s = s.trim_start_matches(Self::name());
+
+        let (dimensions, gravity) = match s.find('-') {
+            Some(index) => (&s[..index], Gravity::from_str(&s[index + 1..])?),
+            None => (s, Gravity::Center),
+        };
+
+        let (width, height) = parse_dimensions(dimensions)?;
+        Some(Box::new(Crop(width, height, gravity)))
+    }
+
+    fn path(&self, mut path: PathBuf) -> PathBuf {
+        path.push(Self::name());
+        path.push(format!("{}x{}", self.0, self.1));
+        path.push(self.2.as_str());
+        path
+    }
+
+    fn check(&self, limits: &Limits) -> Result<(), UploadError> {
+        check_dimensions(Self::name(), self.0, self.1, limits)
+    }
+
+    fn process(&self, wand: &mut MagickWand) -> Result<bool, UploadError> {
+        debug!("Crop");
+        crop(wand, self.0, self.1, self.2)
+    }
+}
+
+pub(crate) struct Fill(usize, usize);
+
+impl Processor for Fill {
+    fn name() -> &'static str
+    where
+        Self: Sized,
+    {
+        "fill"
+    }
+
+    fn is_processor(s: &str) -> bool
+    where
+        Self: Sized,
+    {
+        s.starts_with(Self::name())
+    }
+
+    fn parse(s: &str) -> Option<Box<dyn Processor + Send>>
+    where
+        Self: Sized,
+    {
+        let (width, height) = parse_dimensions(s.trim_start_matches(Self::name()))?;
+        Some(Box::new(Fill(width, height)))
+    }
+
+    fn path(&self, mut path: PathBuf) -> PathBuf {
+        path.push(Self::name());
+        path.push(format!("{}x{}", self.0, self.1));
+        path
+    }
+
+    fn check(&self, limits: &Limits) -> Result<(), UploadError> {
+        check_dimensions(Self::name(), self.0, self.1, limits)
+    }
+
+    fn process(&self, wand: &mut MagickWand) -> Result<bool, UploadError> {
+        debug!("Fill");
+        let width = wand.get_image_width();
+        let height = wand.get_image_height();
+
+        if width == self.0 && height == self.1 {
+            return Ok(false);
+        }
+
+        // Scale so the image covers the whole rectangle, then trim the overflow
+        let ratio = f64::max(self.0 as f64 / width as f64, self.1 as f64 / height as f64);
+
+        let new_width = ((width as f64 * ratio).ceil() as usize).max(self.0);
+        let new_height = ((height as f64 * ratio).ceil() as usize).max(self.1);
+
+        wand.op(|w| w.sample_image(new_width, new_height))?;
+        crop(wand, self.0, self.1, Gravity::Center)?;
+        Ok(true)
+    }
+}
+
+pub(crate) struct OutputFormat(Format);
+
+impl Processor for OutputFormat {
+    fn name() -> &'static str
+    where
+        Self: Sized,
+    {
+        "format"
+    }
+
+    fn is_processor(s: &str) -> bool
+    where
+        Self: Sized,
+    {
+        s.starts_with(Self::name())
+    }
+
+    fn parse(s: &str) -> Option<Box<dyn Processor + Send>>
+    where
+        Self: Sized,
+    {
+        let format = s
+            .trim_start_matches(Self::name())
+            .trim_start_matches('-')
+            .parse()
+            .ok()?;
+        Some(Box::new(OutputFormat(format)))
+    }
+
+    fn path(&self, mut path: PathBuf) -> PathBuf {
+        path.push(Self::name());
+        path.push(self.0.to_magick_format().to_lowercase());
+        path
+    }
+
+    // The encoding itself happens once the whole chain has run
+    fn process(&self, _: &mut MagickWand) -> Result<bool, UploadError> {
+        Ok(false)
+    }
+
+    fn output_format(&self) -> Option<Format> {
+        Some(self.0.clone())
+    }
+}
+
+// parse a `{width}x{height}` pair
+pub(crate) fn parse_dimensions(s: &str) -> Option<(usize, usize)> {
+    let mut iter = s.splitn(2, 'x');
+    let width = iter.next()?.parse().ok()?;
+    let height = iter.next()?.parse().ok()?;
+
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    Some((width, height))
+}
+
+fn crop(
+    wand: &mut MagickWand,
+    width: usize,
+    height: usize,
+    gravity: Gravity,
+) -> Result<bool, UploadError> {
+    let image_width = wand.get_image_width();
+    let image_height = wand.get_image_height();
+
+    let width = width.min(image_width);
+    let height = height.min(image_height);
+
+    if width == image_width && height == image_height {
+        return Ok(false);
+    }
+
+    let (x, y) = crop_offset(wand, width, height, gravity)?;
+
+    debug!("Cropping {}x{} at {},{}", width, height, x, y);
+    wand.op(|w| w.crop_image(width, height, x as isize, y as isize))?;
+    // Like `+repage`, drop the original canvas so gif frames aren't drawn at the old offset
+    wand.op(|w| w.set_image_page(width, height, 0, 0))?;
+    Ok(true)
+}
+
+// Where the top left corner of a crop of the given size lands
+fn crop_offset(
+    wand: &MagickWand,
+    width: usize,
+    height: usize,
+    gravity: Gravity,
+) -> Result<(usize, usize), UploadError> {
+    let image_width = wand.get_image_width();
+    let image_height = wand.get_image_height();
+
+    match gravity {
+        Gravity::Center => Ok(((image_width - width) / 2, (image_height - height) / 2)),
+        Gravity::North => Ok(((image_width - width) / 2, 0)),
+        Gravity::Entropy => entropy_offset(wand, width, height),
+    }
+}
+
+// The longest side of the copy used when searching for the busiest region of an image
+const ENTROPY_SAMPLE_SIZE: usize = 256;
+
+// Find the crop offset that keeps the most detailed part of the image
+//
+// Like libvips' entropy crop, this repeatedly trims a slice off whichever edge of the remaining
+// region has the lower entropy until the region is the requested size. The search runs on a
+// shrunken grayscale copy of the image to keep it cheap.
+fn entropy_offset(
+    wand: &MagickWand,
+    width: usize,
+    height: usize,
+) -> Result<(usize, usize), UploadError> {
+    let image_width = wand.get_image_width();
+    let image_height = wand.get_image_height();
+
+    let scale = f64::min(
+        1.0,
+        ENTROPY_SAMPLE_SIZE as f64 / image_width.max(image_height) as f64,
+    );
+    let sample_width = ((image_width as f64 * scale) as usize).max(1);
+    let sample_height = ((image_height as f64 * scale) as usize).max(1);
+
+    let sample = wand.clone();
+    sample.op(|w| w.sample_image(sample_width, sample_height))?;
+    let pixels = sample
+        .export_image_pixels(0, 0, sample_width, sample_height, "I")
+        .ok_or_else(|| UploadError::Wand("Failed to export pixels".to_owned()))?;
+
+    let target_width = ((width as f64 * scale) as usize).max(1);
+    let target_height = ((height as f64 * scale) as usize).max(1);
+
+    let (mut left, mut right) = (0, sample_width);
+    let (mut top, mut bottom) = (0, sample_height);
+
+    while right - left > target_width {
+        let slice = ((right - left) / 10)
+            .max(1)
+            .min(right - left - target_width);
+
+        let left_entropy = entropy(&pixels, sample_width, (left, left + slice), (top, bottom));
+        let right_entropy = entropy(&pixels, sample_width, (right - slice, right), (top, bottom));
+
+        if left_entropy < right_entropy {
+            left += slice;
+        } else {
+            right -= slice;
+        }
+    }
+
+    while bottom - top > target_height {
+        let slice = ((bottom - top) / 10)
+            .max(1)
+            .min(bottom - top - target_height);
+
+        let top_entropy = entropy(&pixels, sample_width, (left, right), (top, top + slice));
+        let bottom_entropy = entropy(
+            &pixels,
+            sample_width,
+            (left, right),
+            (bottom - slice, bottom),
+        );
+
+        if top_entropy < bottom_entropy {
+            top += slice;
+        } else {
+            bottom -= slice;
+        }
+    }
+
+    let x = ((left as f64 / scale) as usize).min(image_width - width);
+    let y = ((top as f64 / scale) as usize).min(image_height - height);
+
+    Ok((x, y))
+}
+
+// Shannon entropy of the grayscale histogram for a region of pixels
+fn entropy(
+    pixels: &[u8],
+    stride: usize,
+    (left, right): (usize, usize),
+    (top, bottom): (usize, usize),
+) -> f64 {
+    let mut histogram = [0usize; 256];
+
+    for row in top..bottom {
+        for pixel in &pixels[row * stride + left..row * stride + right] {
+            histogram[*pixel as usize] += 1;
+        }
+    }
+
+    let total = ((right - left) * (bottom - top)) as f64;
+
+    histogram
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
 macro_rules! parse {
     ($x:ident, $y:expr, $z:expr) => {{
         if $x::is_processor($y) && $x::is_whitelisted($z) {
@@ -170,6 +654,25 @@ macro_rules! parse {
 
 pub(crate) struct ProcessChain {
     inner: Vec<Box<dyn Processor + Send>>,
+    segments: Vec<String>,
+}
+
+impl ProcessChain {
+    /// The format requested by the last format step in the chain, if any
+    pub(crate) fn output_format(&self) -> Option<Format> {
+        self.inner.iter().rev().find_map(|p| p.output_format())
+    }
+
+    /// Append a step encoding the result as the given format
+    pub(crate) fn push_format(&mut self, format: Format) {
+        self.segments.push(format!("{}-{}", OutputFormat::name(), format.as_str()));
+        self.inner.push(Box::new(OutputFormat(format)));
+    }
+
+    /// The segments the chain was parsed from, for rebuilding it in another process
+    pub(crate) fn segments(&self) -> &[String] {
+        &self.segments
+    }
 }
 
 impl std::fmt::Debug for ProcessChain {
@@ -180,22 +683,75 @@ impl std::fmt::Debug for ProcessChain {
     }
 }
 
+/// Parse the requested transformations
+///
+/// Segments that don't parse or aren't whitelisted are skipped, unless `strict` is set, in which
+/// case the first such segment is returned as an error. Parameters outside the provided limits are
+/// always an error.
 #[instrument]
-pub(crate) fn build_chain(args: &[String], whitelist: Option<&HashSet<String>>) -> ProcessChain {
-    let inner = args
-        .into_iter()
-        .filter_map(|arg| {
-            parse!(Identity, arg.as_str(), whitelist);
-            parse!(Thumbnail, arg.as_str(), whitelist);
-            parse!(Blur, arg.as_str(), whitelist);
+pub(crate) fn build_chain(
+    args: &[String],
+    whitelist: Option<&HashSet<String>>,
+    strict: bool,
+    limits: &Limits,
+) -> Result<ProcessChain, UploadError> {
+    let mut inner = Vec::new();
+    let mut segments = Vec::new();
+
+    for arg in args {
+        match parse_processor(arg, whitelist) {
+            Some(processor) => {
+                processor.check(limits)?;
+                inner.push(processor);
+                segments.push(arg.to_owned());
+
+                if inner.len() > limits.max_chain_length {
+                    return Err(limit_err(format!(
+                        "more than {} transformations requested",
+                        limits.max_chain_length
+                    )));
+                }
+            }
+            None if strict => {
+                debug!("Rejecting {}, invalid or not whitelisted", arg);
+                return Err(UploadError::InvalidTransformation(arg.to_owned()));
+            }
+            None => debug!("Skipping {}, invalid or not whitelisted", arg),
+        }
+    }
 
-            debug!("Skipping {}, invalid or whitelisted", arg);
+    Ok(ProcessChain { inner, segments })
+}
 
-            None
-        })
-        .collect();
+/// Rebuild a chain from the segments of one that was already checked by `build_chain`
+pub(crate) fn parse_chain(segments: &[String]) -> ProcessChain {
+    let mut inner = Vec::new();
+
+    for segment in segments {
+        if let Some(processor) = parse_processor(segment, None) {
+            inner.push(processor);
+        }
+    }
 
-    ProcessChain { inner }
+    ProcessChain {
+        inner,
+        segments: segments.to_vec(),
+    }
+}
+
+fn parse_processor(
+    arg: &str,
+    whitelist: Option<&HashSet<String>>,
+) -> Option<Box<dyn Processor + Send>> {
+    parse!(Identity, arg, whitelist);
+    parse!(Thumbnail, arg, whitelist);
+    parse!(Blur, arg, whitelist);
+    parse!(Resize, arg, whitelist);
+    parse!(Crop, arg, whitelist);
+    parse!(Fill, arg, whitelist);
+    parse!(OutputFormat, arg, whitelist);
+
+    None
 }
 
 pub(crate) fn build_path(base: PathBuf, chain: &ProcessChain, filename: String) -> PathBuf {
@@ -213,36 +769,190 @@ pub(crate) async fn process_image(
     original_file: PathBuf,
     chain: ProcessChain,
 ) -> Result<Option<Bytes>, UploadError> {
-    let original_path_str = ptos(&original_file)?;
-    let span = Span::current();
+    let start = Instant::now();
 
-    let opt = web::block(move || {
-        let entered = span.enter();
+    let res = if crate::CONFIG.sandbox() {
+        magick_pool::run(move || sandbox::process(&original_file, chain.segments())).await
+    } else {
+        let original_path_str = ptos(&original_file)?;
+        magick_pool::run(move || process_file(&original_path_str, chain)).await
+    };
 
-        let mut wand = MagickWand::new();
-        debug!("Reading image");
-        wand.op(|w| w.read_image(&original_path_str))?;
+    metrics::processed(start);
+    res
+}
 
-        let format = wand.op(|w| w.get_image_format())?;
+/// Apply the chain to the file at the given path, returning the encoded result if it changed
+pub(crate) fn process_file(
+    original_path_str: &str,
+    chain: ProcessChain,
+) -> Result<Option<Bytes>, UploadError> {
+    let mut wand = MagickWand::new();
+    debug!("Reading image");
+    wand.op(|w| w.read_image(original_path_str))?;
+
+    let original_format = wand.op(|w| w.get_image_format())?;
+    let format = chain
+        .output_format()
+        .map(|f| f.to_magick_format().to_owned())
+        .unwrap_or_else(|| original_format.clone());
+
+    debug!("Processing image");
+    let mut changed = format != original_format;
+
+    for processor in chain.inner.into_iter() {
+        debug!("Step");
+        changed |= processor.process(&mut wand)?;
+        debug!("Step complete");
+    }
 
-        debug!("Processing image");
-        let mut changed = false;
+    if changed {
+        let vec = wand.op(|w| w.write_image_blob(&format))?;
+        return Ok(Some(Bytes::from(vec)));
+    }
 
-        for processor in chain.inner.into_iter() {
-            debug!("Step");
-            changed |= processor.process(&mut wand)?;
-            debug!("Step complete");
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate::tests::init;
+
+    // A flat gray image with a checkerboard filling its bottom right corner
+    fn image(width: usize, height: usize, detail: usize) -> MagickWand {
+        init();
+
+        let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+        for y in 0..height {
+            for x in 0..width {
+                let value = if x >= width - detail && y >= height - detail {
+                    if (x + y) % 2 == 0 {
+                        0
+                    } else {
+                        255
+                    }
+                } else {
+                    128
+                };
+                ppm.extend_from_slice(&[value; 3]);
+            }
         }
 
-        if changed {
-            let vec = wand.op(|w| w.write_image_blob(&format))?;
-            return Ok(Some(Bytes::from(vec)));
+        let path = std::env::temp_dir().join(format!("{}.ppm", uuid::Uuid::new_v4()));
+        std::fs::write(&path, ppm).unwrap();
+
+        let wand = MagickWand::new();
+        let res = wand.op(|w| w.read_image(&ptos(&path).unwrap()));
+        std::fs::remove_file(&path).unwrap();
+        res.unwrap();
+
+        wand
+    }
+
+    #[test]
+    fn centers_crops() {
+        let wand = image(100, 80, 0);
+        let offset = crop_offset(&wand, 40, 40, Gravity::Center).unwrap();
+        assert_eq!(offset, (30, 20));
+    }
+
+    #[test]
+    fn crops_from_the_top() {
+        let wand = image(100, 80, 0);
+        let offset = crop_offset(&wand, 40, 40, Gravity::North).unwrap();
+        assert_eq!(offset, (30, 0));
+    }
+
+    #[test]
+    fn crops_toward_detail() {
+        let wand = image(100, 100, 30);
+        let offset = crop_offset(&wand, 40, 40, Gravity::Entropy).unwrap();
+        assert_eq!(offset, (60, 60));
+    }
+
+    #[test]
+    fn crops_reset_the_page() {
+        for gravity in &[Gravity::Center, Gravity::North, Gravity::Entropy] {
+            let mut wand = image(100, 80, 30);
+
+            assert!(crop(&mut wand, 40, 30, *gravity).unwrap());
+            assert_eq!(wand.get_image_width(), 40);
+            assert_eq!(wand.get_image_height(), 30);
+            assert_eq!(wand.get_image_page(), (40, 30, 0, 0));
         }
+    }
+
+    #[test]
+    fn fills_reset_the_page() {
+        let mut wand = image(100, 80, 0);
+
+        assert!(Fill(50, 50).process(&mut wand).unwrap());
+        assert_eq!(wand.get_image_width(), 50);
+        assert_eq!(wand.get_image_height(), 50);
+        assert_eq!(wand.get_image_page(), (50, 50, 0, 0));
+    }
+
+    fn limits() -> Limits {
+        Limits {
+            max_thumbnail_size: 4096,
+            thumbnail_sizes: Some(vec![64, 128].into_iter().collect()),
+            max_dimension: 4096,
+            dimensions: Some(vec![(256, 256), (1920, 1080)].into_iter().collect()),
+            max_blur_sigma: 20.0,
+            blur_sigmas: Some(vec![1.0, 2.5]),
+            max_chain_length: 10,
+        }
+    }
+
+    fn chain(segment: &str) -> Result<ProcessChain, UploadError> {
+        build_chain(&[segment.to_owned()], None, true, &limits())
+    }
+
+    #[test]
+    fn allows_listed_parameters() {
+        for segment in &[
+            "thumbnail64",
+            "resize1920x1080",
+            "crop256x256-north",
+            "fill256x256",
+            "blur2.5",
+        ] {
+            assert!(chain(segment).is_ok(), "{} was rejected", segment);
+        }
+    }
 
-        drop(entered);
-        Ok(None) as Result<Option<Bytes>, UploadError>
-    })
-    .await?;
+    #[test]
+    fn rejects_unlisted_parameters() {
+        for segment in &[
+            "thumbnail65",
+            "resize1920x1081",
+            "crop255x256",
+            "fill256x257",
+            "blur2.4",
+        ] {
+            assert!(
+                matches!(chain(segment), Err(UploadError::TransformationLimit(_))),
+                "{} was allowed",
+                segment
+            );
+        }
+    }
 
-    Ok(opt)
+    #[test]
+    fn rejects_invalid_blur_sigmas() {
+        let limits = Limits {
+            blur_sigmas: None,
+            ..limits()
+        };
+
+        for segment in &["blurNaN", "blurinf", "blur-1"] {
+            let res = build_chain(&[segment.to_string()], None, true, &limits);
+            assert!(
+                matches!(res, Err(UploadError::TransformationLimit(_))),
+                "{} was allowed",
+                segment
+            );
+        }
+    }
 }
@@ -0,0 +1,339 @@
+use crate::{
+    config::Format,
+    error::UploadError,
+    processor::{parse_chain, process_file},
+    upload_manager::tmp_file,
+    validate::{ptos, read_info, validate_file, ImageInfo, ImageLimits},
+};
+use actix_web::ResponseError;
+use bytes::Bytes;
+use std::{
+    io::{Read, Write},
+    path::PathBuf,
+    process::{Child, Command, ExitStatus, Stdio},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use tracing::{debug, instrument, warn};
+
+const VALIDATE: &str = "sandbox-validate";
+const PROCESS: &str = "sandbox-process";
+const INFO: &str = "sandbox-info";
+
+// The exit code for images the child refused, as opposed to the child itself failing
+const REJECTED: i32 = 2;
+
+/// Validate the file at the given path in a child process, rewriting it with the cleaned image
+#[instrument]
+pub(crate) fn validate(
+    tmpfile: &PathBuf,
+    format: Option<Format>,
+    limits: &ImageLimits,
+) -> Result<mime::Mime, UploadError> {
+    let mut args = vec![
+        "--max-width".to_owned(),
+        limits.max_width.to_string(),
+        "--max-height".to_owned(),
+        limits.max_height.to_string(),
+        "--max-area".to_owned(),
+        limits.max_area.to_string(),
+        "--max-frame-count".to_owned(),
+        limits.max_frame_count.to_string(),
+    ];
+
+    if let Some(format) = format {
+        args.push("--format".to_owned());
+        args.push(format.as_str().to_owned());
+    }
+
+    let output = spawn(VALIDATE, &args, std::fs::read(tmpfile)?)?;
+
+    // The content type comes first, on a line of its own
+    let index = output
+        .iter()
+        .position(|b| *b == b'\n')
+        .ok_or_else(|| UploadError::Sandbox("missing content type".to_owned()))?;
+
+    let content_type = String::from_utf8(output[..index].to_vec())?
+        .parse()
+        .map_err(|_| UploadError::Sandbox("invalid content type".to_owned()))?;
+
+    std::fs::write(tmpfile, &output[index + 1..])?;
+
+    Ok(content_type)
+}
+
+/// Apply an already-checked chain to the file at the given path in a child process
+#[instrument]
+pub(crate) fn process(
+    original_file: &PathBuf,
+    segments: &[String],
+) -> Result<Option<Bytes>, UploadError> {
+    let output = spawn(PROCESS, segments, std::fs::read(original_file)?)?;
+
+    // No output means the chain didn't change the image
+    if output.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(Bytes::from(output)))
+}
+
+/// Read the format, dimensions and frame count of the file at the given path in a child process
+#[instrument]
+pub(crate) fn info(file: &PathBuf) -> Result<ImageInfo, UploadError> {
+    let output = String::from_utf8(spawn(INFO, &[], std::fs::read(file)?)?)?;
+
+    // One field per line, in the order they're written by `run_info`
+    let invalid = || UploadError::Sandbox("invalid image info".to_owned());
+    let mut lines = output.lines();
+    let mut next = || lines.next().ok_or_else(invalid);
+
+    Ok(ImageInfo {
+        content_type: next()?.parse().map_err(|_| invalid())?,
+        width: next()?.parse().map_err(|_| invalid())?,
+        height: next()?.parse().map_err(|_| invalid())?,
+        frames: next()?.parse().map_err(|_| invalid())?,
+    })
+}
+
+fn spawn(subcommand: &str, args: &[String], input: Vec<u8>) -> Result<Vec<u8>, UploadError> {
+    debug!("Spawning {}", subcommand);
+
+    // The child inherits ImageMagick's resource limits from our environment. It also gets a time
+    // limit, which ImageMagick enforces by exiting the child.
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .env(
+            "MAGICK_TIME_LIMIT",
+            crate::CONFIG.magick_timeout().as_secs().to_string(),
+        )
+        .arg("--path")
+        .arg(crate::CONFIG.data_dir())
+        .arg(subcommand)
+        .args(args);
+
+    run_child(command, input, crate::magick_pool::deadline())
+}
+
+fn run_child(
+    mut command: Command,
+    input: Vec<u8>,
+    deadline: Option<Instant>,
+) -> Result<Vec<u8>, UploadError> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Drain the output while it runs, so a full pipe can't stall the child
+    let stdout = child.stdout.take().map(read_in_background);
+    let stderr = child.stderr.take().map(read_in_background);
+
+    // The child reads all of its input before writing anything, so this can't deadlock. Dropping
+    // stdin afterwards closes it.
+    let written = match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(&input),
+        None => Ok(()),
+    };
+
+    let status = match written {
+        Ok(()) => wait(&mut child, deadline)?,
+        // The child stopped reading, e.g. after hitting its time limit or crashing, so its exit
+        // status and stderr say more than the write error does
+        Err(e) => {
+            warn!("Failed to write to sandboxed child, {}", e);
+            let _ = child.kill();
+            child.wait()?
+        }
+    };
+
+    let stdout = join(stdout)?;
+    let stderr = join(stderr)?;
+
+    if status.success() {
+        return Ok(stdout);
+    }
+
+    let msg = String::from_utf8_lossy(&stderr).trim().to_owned();
+
+    match status.code() {
+        Some(REJECTED) => Err(UploadError::Rejected(msg)),
+        Some(code) => Err(UploadError::Sandbox(format!(
+            "exited with {}, {}",
+            code, msg
+        ))),
+        None => Err(UploadError::Sandbox(format!(
+            "terminated by a signal, {}",
+            msg
+        ))),
+    }
+}
+
+// Wait for the child to exit, killing it once the pool has given up on it
+fn wait(child: &mut Child, deadline: Option<Instant>) -> Result<ExitStatus, UploadError> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+
+        if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            warn!("Killing sandboxed child that ran past its deadline");
+            child.kill()?;
+            child.wait()?;
+            return Err(UploadError::Timeout);
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn read_in_background<R>(mut reader: R) -> JoinHandle<std::io::Result<Vec<u8>>>
+where
+    R: Read + Send + 'static,
+{
+    std::thread::spawn(move || {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    })
+}
+
+fn join(handle: Option<JoinHandle<std::io::Result<Vec<u8>>>>) -> Result<Vec<u8>, UploadError> {
+    match handle {
+        Some(handle) => Ok(handle.join().map_err(|_| UploadError::Canceled)??),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Validate an image from stdin, writing its content type and the cleaned image to stdout
+pub(crate) fn run_validate(format: Option<Format>, limits: ImageLimits) -> Result<(), UploadError> {
+    with_stdin_file(|tmpfile| {
+        let content_type = validate_file(tmpfile, format, &limits)?;
+        let bytes = std::fs::read(tmpfile)?;
+
+        let mut stdout = std::io::stdout();
+        writeln!(stdout, "{}", content_type)?;
+        stdout.write_all(&bytes)?;
+        stdout.flush()?;
+
+        Ok(())
+    })
+}
+
+/// Process an image from stdin, writing the result to stdout if it changed
+pub(crate) fn run_process(segments: Vec<String>) -> Result<(), UploadError> {
+    with_stdin_file(|tmpfile| {
+        if let Some(bytes) = process_file(&ptos(tmpfile)?, parse_chain(&segments))? {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&bytes)?;
+            stdout.flush()?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Read an image's format, dimensions and frame count from stdin, writing them to stdout
+pub(crate) fn run_info() -> Result<(), UploadError> {
+    with_stdin_file(|tmpfile| {
+        let info = read_info(tmpfile)?;
+
+        let mut stdout = std::io::stdout();
+        writeln!(stdout, "{}", info.content_type)?;
+        writeln!(stdout, "{}", info.width)?;
+        writeln!(stdout, "{}", info.height)?;
+        writeln!(stdout, "{}", info.frames)?;
+        stdout.flush()?;
+
+        Ok(())
+    })
+}
+
+fn with_stdin_file<F>(f: F) -> Result<(), UploadError>
+where
+    F: FnOnce(&PathBuf) -> Result<(), UploadError>,
+{
+    let mut input = Vec::new();
+    std::io::stdin().read_to_end(&mut input)?;
+
+    let tmpfile = tmp_file();
+    if let Some(parent) = tmpfile.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&tmpfile, input)?;
+
+    let res = f(&tmpfile);
+    let _ = std::fs::remove_file(&tmpfile);
+    res
+}
+
+/// Exit the child process, reporting any error to the parent
+pub(crate) fn exit(res: Result<(), UploadError>) -> ! {
+    match res {
+        Ok(()) => std::process::exit(0),
+        Err(e) => {
+            eprintln!("{}", e);
+
+            if e.status_code().is_client_error() {
+                std::process::exit(REJECTED);
+            }
+
+            std::process::exit(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[test]
+    fn returns_output() {
+        let output = run_child(shell("cat"), b"image".to_vec(), None).unwrap();
+        assert_eq!(output, b"image");
+    }
+
+    #[test]
+    fn reports_rejections() {
+        let script = "cat > /dev/null; echo too big >&2; exit 2";
+        let res = run_child(shell(script), vec![], None);
+        assert!(matches!(res, Err(UploadError::Rejected(msg)) if msg == "too big"));
+    }
+
+    // More input than a pipe holds, so writing fails once the child exits without reading it
+    #[test]
+    fn reports_children_that_stop_reading() {
+        let res = run_child(
+            shell("exec 0<&-; echo time limit exceeded >&2; exit 1"),
+            vec![0; 4 * 1024 * 1024],
+            None,
+        );
+
+        assert!(
+            matches!(&res, Err(UploadError::Sandbox(msg)) if msg.contains("time limit exceeded")),
+            "expected the child's error, got {:?}",
+            res
+        );
+    }
+
+    #[test]
+    fn kills_children_past_their_deadline() {
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let res = run_child(
+            shell("cat > /dev/null; exec sleep 10"),
+            vec![],
+            Some(deadline),
+        );
+
+        assert!(matches!(res, Err(UploadError::Timeout)));
+        assert!(Instant::now() < deadline + Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,174 @@
+use crate::error::UploadError;
+use actix_web::http::{header::AUTHORIZATION, HeaderMap};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use tracing::{debug, instrument};
+
+const API_TOKEN_HEADER: &str = "X-Api-Token";
+
+/// Check the signature for a request path
+///
+/// The signature is a hex-encoded HMAC-SHA256 over the path following `/image/`, e.g.
+/// `thumbnail256/asdf.png`. When an expiry is provided, the message is the path followed by a
+/// newline and the expiry as seconds since the unix epoch, e.g. `thumbnail256/asdf.png\n1600000000`
+#[instrument(skip(key, sig))]
+pub(crate) fn verify(
+    key: &[u8],
+    path: &str,
+    sig: Option<&str>,
+    expires: Option<u64>,
+) -> Result<(), UploadError> {
+    let sig = sig.ok_or(UploadError::InvalidSignature)?;
+    let sig = hex::decode(sig).map_err(|_| UploadError::InvalidSignature)?;
+
+    let mut mac = Hmac::<Sha256>::new_varkey(key).map_err(|_| UploadError::InvalidSignature)?;
+    mac.update(path.as_bytes());
+    if let Some(expires) = expires {
+        mac.update(format!("\n{}", expires).as_bytes());
+    }
+
+    // verify compares in constant time
+    mac.verify(&sig).map_err(|_| {
+        debug!("Signature mismatch");
+        UploadError::InvalidSignature
+    })?;
+
+    if let Some(expires) = expires {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        if now > expires {
+            return Err(UploadError::ExpiredSignature);
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that a request carries one of the API keys
+///
+/// The key is read from the `X-Api-Token` header, or from an `Authorization: Bearer` header
+#[instrument(skip(keys, headers))]
+pub(crate) fn verify_api_key(keys: &[String], headers: &HeaderMap) -> Result<(), UploadError> {
+    let provided = headers
+        .get(API_TOKEN_HEADER)
+        .and_then(|token| token.to_str().ok())
+        .or_else(|| {
+            headers
+                .get(AUTHORIZATION)
+                .and_then(|auth| auth.to_str().ok())
+                .and_then(|auth| auth.strip_prefix("Bearer "))
+        })
+        .ok_or(UploadError::InvalidApiKey)?;
+
+    // Check every key, so the time taken doesn't reveal which one matched
+    let matched = keys.iter().fold(false, |matched, key| {
+        constant_time_eq(key.as_bytes(), provided.as_bytes()) | matched
+    });
+
+    if !matched {
+        debug!("API key mismatch");
+        return Err(UploadError::InvalidApiKey);
+    }
+
+    Ok(())
+}
+
+// Compare without exiting early, so the time taken doesn't reveal how much of a secret matched
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"signing key";
+    const PATH: &str = "thumbnail256/asdf.png";
+
+    fn sign(key: &[u8], message: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+        mac.update(message.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn in_an_hour() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600
+    }
+
+    #[test]
+    fn accepts_valid_signatures() {
+        let sig = sign(KEY, PATH);
+        assert!(verify(KEY, PATH, Some(&sig), None).is_ok());
+
+        let expires = in_an_hour();
+        let sig = sign(KEY, &format!("{}\n{}", PATH, expires));
+        assert!(verify(KEY, PATH, Some(&sig), Some(expires)).is_ok());
+    }
+
+    #[test]
+    fn rejects_tampered_paths() {
+        let sig = sign(KEY, PATH);
+        let res = verify(KEY, "thumbnail512/asdf.png", Some(&sig), None);
+        assert!(matches!(res, Err(UploadError::InvalidSignature)));
+    }
+
+    #[test]
+    fn rejects_tampered_expiry() {
+        let expires = in_an_hour();
+        let sig = sign(KEY, &format!("{}\n{}", PATH, expires));
+
+        let res = verify(KEY, PATH, Some(&sig), Some(expires + 3600));
+        assert!(matches!(res, Err(UploadError::InvalidSignature)));
+
+        let res = verify(KEY, PATH, Some(&sig), None);
+        assert!(matches!(res, Err(UploadError::InvalidSignature)));
+    }
+
+    #[test]
+    fn rejects_other_keys() {
+        let sig = sign(b"another key", PATH);
+        let res = verify(KEY, PATH, Some(&sig), None);
+        assert!(matches!(res, Err(UploadError::InvalidSignature)));
+    }
+
+    #[test]
+    fn rejects_expired_signatures() {
+        let expires = 1_600_000_000;
+        let sig = sign(KEY, &format!("{}\n{}", PATH, expires));
+        let res = verify(KEY, PATH, Some(&sig), Some(expires));
+        assert!(matches!(res, Err(UploadError::ExpiredSignature)));
+    }
+
+    #[test]
+    fn rejects_missing_signatures() {
+        let res = verify(KEY, PATH, None, None);
+        assert!(matches!(res, Err(UploadError::InvalidSignature)));
+    }
+
+    #[test]
+    fn rejects_malformed_hex() {
+        let mut sig = sign(KEY, PATH);
+        sig.replace_range(..2, "zz");
+
+        for sig in &[sig.as_str(), "abc", ""] {
+            let res = verify(KEY, PATH, Some(sig), None);
+            assert!(matches!(res, Err(UploadError::InvalidSignature)));
+        }
+    }
+
+    #[test]
+    fn compares_in_constant_time() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"secret", b"secre"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{error::UploadError, upload_manager::tmp_file};
+use bytes::Bytes;
+use futures::stream::LocalBoxStream;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tracing::{debug, instrument};
+
+mod file_store;
+mod object_store;
+
+pub(crate) use self::{file_store::FileStore, object_store::ObjectStore};
+
+pub(crate) type BytesStream = LocalBoxStream<'static, Result<Bytes, UploadError>>;
+
+/// A place to keep original images and their variants
+///
+/// Keys are relative, '/'-separated paths such as `abcdefghij.png` or
+/// `thumbnail/256/abcdefghij.png`
+#[async_trait::async_trait(?Send)]
+pub(crate) trait Store: Send + Sync {
+    /// Move a local file into the store, failing if the key is already taken
+    async fn save_file(&self, from: PathBuf, key: &str) -> Result<(), UploadError>;
+
+    /// Write bytes into the store, doing nothing if the key is already taken
+    async fn save_bytes(&self, bytes: Bytes, key: &str) -> Result<(), UploadError>;
+
+    /// Stream the contents stored at the given key
+    async fn to_stream(&self, key: &str) -> Result<BytesStream, UploadError>;
+
+    /// Remove the contents stored at the given key
+    async fn remove(&self, key: &str) -> Result<(), UploadError>;
+
+    /// Check whether anything is stored at the given key
+    async fn exists(&self, key: &str) -> Result<bool, UploadError>;
+
+    /// The on-disk path for a key, if this store keeps files on the local filesystem
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum StoreConfig {
+    File {
+        path: PathBuf,
+    },
+    Object {
+        endpoint: String,
+        bucket: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+        timeout: Duration,
+    },
+}
+
+/// Construct the store described by the provided configuration
+pub(crate) async fn build(config: StoreConfig) -> Result<Arc<dyn Store>, UploadError> {
+    match config {
+        StoreConfig::File { path } => Ok(Arc::new(FileStore::new(path).await?)),
+        StoreConfig::Object {
+            endpoint,
+            bucket,
+            region,
+            access_key,
+            secret_key,
+            timeout,
+        } => Ok(Arc::new(ObjectStore::new(
+            endpoint, bucket, region, access_key, secret_key, timeout,
+        )?)),
+    }
+}
+
+/// A file that can be handed to ImageMagick
+///
+/// Stores that don't keep files on disk have their contents copied into a temporary file, which
+/// is removed when this is dropped
+pub(crate) enum LocalFile {
+    Stored(PathBuf),
+    Temporary(PathBuf),
+}
+
+impl LocalFile {
+    pub(crate) fn path(&self) -> PathBuf {
+        match self {
+            LocalFile::Stored(path) | LocalFile::Temporary(path) => path.clone(),
+        }
+    }
+}
+
+impl Drop for LocalFile {
+    fn drop(&mut self) {
+        if let LocalFile::Temporary(path) = self {
+            debug!("Removing temporary file {:?}", path);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Get a local file for the contents of the given key
+#[instrument(skip(store))]
+pub(crate) async fn to_local_file(store: &dyn Store, key: &str) -> Result<LocalFile, UploadError> {
+    if let Some(path) = store.local_path(key) {
+        return Ok(LocalFile::Stored(path));
+    }
+
+    let tmpfile = tmp_file();
+    if let Some(path) = tmpfile.parent() {
+        debug!("Creating directory {:?}", path);
+        actix_fs::create_dir_all(path.to_owned()).await?;
+    }
+
+    // Construct the guard before writing so a failed write still cleans up after itself
+    let local = LocalFile::Temporary(tmpfile.clone());
+
+    debug!("Copying {} to {:?}", key, tmpfile);
+    let stream = store.to_stream(key).await?;
+    actix_fs::write_stream(tmpfile, stream).await?;
+
+    Ok(local)
+}
@@ -0,0 +1,196 @@
+use crate::{
+    error::UploadError,
+    store::{BytesStream, Store},
+};
+use bytes::Bytes;
+use futures::stream::TryStreamExt;
+use std::path::PathBuf;
+use tracing::{debug, error, instrument};
+
+/// Stores images in a directory on the local filesystem
+#[derive(Clone, Debug)]
+pub(crate) struct FileStore {
+    root_dir: PathBuf,
+}
+
+impl FileStore {
+    pub(crate) async fn new(root_dir: PathBuf) -> Result<Self, UploadError> {
+        // Ensure file dir exists
+        actix_fs::create_dir_all(root_dir.clone()).await?;
+
+        Ok(FileStore { root_dir })
+    }
+
+    fn path_for(&self, key: &str) -> PathBuf {
+        let mut path = self.root_dir.clone();
+        path.extend(key.split('/'));
+        path
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Store for FileStore {
+    #[instrument(skip(self))]
+    async fn save_file(&self, from: PathBuf, key: &str) -> Result<(), UploadError> {
+        safe_move_file(from, self.path_for(key)).await
+    }
+
+    #[instrument(skip(self, bytes))]
+    async fn save_bytes(&self, bytes: Bytes, key: &str) -> Result<(), UploadError> {
+        safe_save_file(self.path_for(key), bytes).await
+    }
+
+    #[instrument(skip(self))]
+    async fn to_stream(&self, key: &str) -> Result<BytesStream, UploadError> {
+        let stream = actix_fs::read_to_stream(self.path_for(key)).await?;
+
+        Ok(Box::pin(stream.err_into()))
+    }
+
+    #[instrument(skip(self))]
+    async fn remove(&self, key: &str) -> Result<(), UploadError> {
+        actix_fs::remove_file(self.path_for(key)).await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn exists(&self, key: &str) -> Result<bool, UploadError> {
+        if let Err(e) = actix_fs::metadata(self.path_for(key)).await {
+            if e.kind() == Some(std::io::ErrorKind::NotFound) {
+                return Ok(false);
+            }
+            return Err(e.into());
+        }
+
+        Ok(true)
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path_for(key))
+    }
+}
+
+#[instrument]
+async fn safe_move_file(from: PathBuf, to: PathBuf) -> Result<(), UploadError> {
+    if let Some(path) = to.parent() {
+        debug!("Creating directory {:?}", path);
+        actix_fs::create_dir_all(path.to_owned()).await?;
+    }
+
+    debug!("Checking if {:?} already exists", to);
+    if let Err(e) = actix_fs::metadata(to.clone()).await {
+        if e.kind() != Some(std::io::ErrorKind::NotFound) {
+            return Err(e.into());
+        }
+    } else {
+        return Err(UploadError::FileExists);
+    }
+
+    debug!("Moving {:?} to {:?}", from, to);
+    actix_fs::copy(from.clone(), to).await?;
+    actix_fs::remove_file(from).await?;
+    Ok(())
+}
+
+// Try writing to a file
+#[instrument(skip(bytes))]
+async fn safe_save_file(path: PathBuf, bytes: Bytes) -> Result<(), UploadError> {
+    if let Some(path) = path.parent() {
+        // create the directory for the file
+        debug!("Creating directory {:?}", path);
+        actix_fs::create_dir_all(path.to_owned()).await?;
+    }
+
+    // Only write the file if it doesn't already exist
+    debug!("Checking if {:?} already exists", path);
+    if let Err(e) = actix_fs::metadata(path.clone()).await {
+        if e.kind() != Some(std::io::ErrorKind::NotFound) {
+            return Err(e.into());
+        }
+    } else {
+        return Ok(());
+    }
+
+    // Open the file for writing
+    debug!("Creating {:?}", path);
+    let file = actix_fs::file::create(path.clone()).await?;
+
+    // try writing
+    debug!("Writing to {:?}", path);
+    if let Err(e) = actix_fs::file::write(file, bytes).await {
+        error!("Error writing {:?}, {}", path, e);
+        // remove file if writing failed before completion
+        actix_fs::remove_file(path).await?;
+        return Err(e.into());
+    }
+    debug!("{:?} written", path);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(store: &FileStore, key: &str) -> Vec<u8> {
+        store
+            .to_stream(key)
+            .await
+            .unwrap()
+            .try_fold(Vec::new(), |mut acc, bytes| async move {
+                acc.extend_from_slice(&bytes);
+                Ok(acc)
+            })
+            .await
+            .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn stores_files() {
+        let root_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let store = FileStore::new(root_dir.clone()).await.unwrap();
+        let key = "thumbnail/256/a.png";
+
+        assert!(!store.exists(key).await.unwrap());
+        store
+            .save_bytes(Bytes::from_static(b"variant"), key)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.local_path(key),
+            Some(root_dir.join("thumbnail/256/a.png"))
+        );
+        assert_eq!(read(&store, key).await, b"variant");
+
+        // Saving bytes over an existing key leaves it alone
+        store
+            .save_bytes(Bytes::from_static(b"other"), key)
+            .await
+            .unwrap();
+        assert_eq!(read(&store, key).await, b"variant");
+
+        store.remove(key).await.unwrap();
+        assert!(!store.exists(key).await.unwrap());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn moves_files() {
+        let root_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let store = FileStore::new(root_dir.clone()).await.unwrap();
+        let from = root_dir.join("upload");
+
+        std::fs::write(&from, b"original").unwrap();
+        store.save_file(from.clone(), "a.png").await.unwrap();
+        assert!(!from.exists());
+        assert_eq!(read(&store, "a.png").await, b"original");
+
+        std::fs::write(&from, b"replacement").unwrap();
+        let res = store.save_file(from.clone(), "a.png").await;
+        assert!(matches!(res, Err(UploadError::FileExists)));
+        assert_eq!(read(&store, "a.png").await, b"original");
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+}
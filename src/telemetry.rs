@@ -0,0 +1,299 @@
+use crate::config::{LogFormat, LogRotation};
+use actix_web::http::HeaderMap;
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    sdk::{propagation::TraceContextPropagator, trace::Tracer},
+};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+use tracing_appender::{
+    non_blocking::{NonBlocking, WorkerGuard},
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    fmt,
+    layer::{Layer, Layered, SubscriberExt},
+    util::SubscriberInitExt,
+    EnvFilter, Registry,
+};
+
+#[cfg(feature = "otlp")]
+use futures::{
+    future::BoxFuture,
+    stream::{BoxStream, StreamExt},
+};
+#[cfg(feature = "otlp")]
+use opentelemetry::{
+    runtime::Runtime,
+    sdk::{trace, Resource},
+    KeyValue,
+};
+#[cfg(feature = "otlp")]
+use std::time::Duration;
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Drives the batch exporter on a thread of its own
+///
+/// opentelemetry's own runtimes need tokio 1 or async-std, while actix runs on tokio 0.2, so the
+/// export loop gets a plain executor and timers that don't depend on either
+#[cfg(feature = "otlp")]
+#[derive(Clone, Debug)]
+struct ExportThread;
+
+#[cfg(feature = "otlp")]
+impl Runtime for ExportThread {
+    type Interval = BoxStream<'static, ()>;
+    type Delay = futures_timer::Delay;
+
+    fn interval(&self, duration: Duration) -> Self::Interval {
+        futures::stream::unfold((), move |()| async move {
+            futures_timer::Delay::new(duration).await;
+            Some(((), ()))
+        })
+        .boxed()
+    }
+
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        std::thread::Builder::new()
+            .name("otlp-exporter".to_owned())
+            .spawn(move || futures::executor::block_on(future))
+            .expect("Failed to spawn trace exporter thread");
+    }
+
+    fn delay(&self, duration: Duration) -> Self::Delay {
+        futures_timer::Delay::new(duration)
+    }
+}
+
+/// Where logs are written, and how they're formatted
+#[derive(Clone, Debug)]
+pub(crate) struct LogConfig {
+    pub(crate) format: LogFormat,
+    pub(crate) file: Option<PathBuf>,
+    pub(crate) rotation: LogRotation,
+}
+
+#[derive(Clone)]
+enum LogWriter {
+    Stdout,
+    File(NonBlocking),
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            LogWriter::Stdout => std::io::stdout().write(buf),
+            LogWriter::File(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            LogWriter::Stdout => std::io::stdout().flush(),
+            LogWriter::File(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Install the global subscriber, exporting spans to an OTLP collector when a URL is given
+///
+/// Logs written to a file are buffered until the returned guard is dropped, so it should be held
+/// until the program exits
+pub(crate) fn init(
+    log: LogConfig,
+    opentelemetry_url: Option<String>,
+) -> Result<Option<WorkerGuard>, anyhow::Error> {
+    let tracer = match opentelemetry_url {
+        Some(url) => {
+            // Continue traces started by our callers, following the W3C traceparent header
+            global::set_text_map_propagator(TraceContextPropagator::new());
+
+            Some(tracer(url)?)
+        }
+        None => None,
+    };
+
+    let (writer, guard) = match log.file {
+        Some(path) => {
+            let directory = path.parent().unwrap_or_else(|| Path::new("."));
+            let prefix = path
+                .file_name()
+                .ok_or_else(|| anyhow::anyhow!("Log file {:?} has no file name", path))?;
+
+            let rotation = match log.rotation {
+                LogRotation::Hourly => Rotation::HOURLY,
+                LogRotation::Daily => Rotation::DAILY,
+                LogRotation::Never => Rotation::NEVER,
+            };
+
+            let (writer, guard) = tracing_appender::non_blocking(RollingFileAppender::new(
+                rotation, directory, prefix,
+            ));
+
+            (LogWriter::File(writer), Some(guard))
+        }
+        None => (LogWriter::Stdout, None),
+    };
+
+    let ansi = guard.is_none();
+    let make_writer = move || writer.clone();
+
+    match log.format {
+        LogFormat::Normal => install(
+            fmt::layer().with_ansi(ansi).with_writer(make_writer),
+            tracer,
+        ),
+        // Span fields like the request id and filename are included so they can be indexed
+        LogFormat::Json => install(
+            fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .with_writer(make_writer),
+            tracer,
+        ),
+    }
+
+    Ok(guard)
+}
+
+// Spans are queued and sent in batches from the export thread, so finishing a request never
+// waits on the collector
+#[cfg(feature = "otlp")]
+fn tracer(url: String) -> Result<Tracer, anyhow::Error> {
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .with_endpoint(url)
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                "pict-rs",
+            )])),
+        )
+        .with_grpcio()
+        .install_batch(ExportThread)?;
+
+    Ok(tracer)
+}
+
+#[cfg(not(feature = "otlp"))]
+fn tracer(_url: String) -> Result<Tracer, anyhow::Error> {
+    Err(anyhow::anyhow!(
+        "Exporting traces needs pict-rs to be built with the otlp feature"
+    ))
+}
+
+fn install<L>(layer: L, tracer: Option<Tracer>)
+where
+    L: Layer<Layered<EnvFilter, Registry>> + Send + Sync + 'static,
+{
+    let subscriber = Registry::default()
+        .with(EnvFilter::from_default_env())
+        .with(layer);
+
+    match tracer {
+        Some(tracer) => subscriber
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .init(),
+        None => subscriber.init(),
+    }
+}
+
+/// The trace context a request's headers carry, if any
+///
+/// Without an exporter configured this is always empty
+pub(crate) fn parent_context(headers: &HeaderMap) -> opentelemetry::Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+/// Flush any spans that haven't been exported yet
+pub(crate) fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+#[cfg(all(test, feature = "otlp"))]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{Span, Tracer as _};
+    use std::{
+        io::{ErrorKind, Read},
+        net::{TcpListener, TcpStream},
+        time::Instant,
+    };
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    fn accept(listener: &TcpListener, deadline: Instant) -> TcpStream {
+        listener.set_nonblocking(true).unwrap();
+
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false).unwrap();
+                    return stream;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock && Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => panic!("The exporter never connected, {}", e),
+            }
+        }
+    }
+
+    // Stands in for a collector just far enough to see what the exporter sends. gRPC doesn't
+    // compress messages by default, so the span's name shows up as-is in the HTTP/2 data frames
+    #[test]
+    fn exports_spans_in_batches() {
+        std::env::set_var("OTEL_BSP_SCHEDULE_DELAY", "100");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tracer = tracer(listener.local_addr().unwrap().to_string()).unwrap();
+
+        let start = Instant::now();
+        tracer.start("otlp-stand-in").end();
+        // Ending a span only queues it for the export thread
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut stream = accept(&listener, deadline);
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+
+        // An empty SETTINGS frame, which gRPC waits for before sending requests, and an
+        // acknowledgement of the client's
+        stream
+            .write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 4, 1, 0, 0, 0, 0])
+            .unwrap();
+
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        while !contains(&received, b"otlp-stand-in") && Instant::now() < deadline {
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+            }
+        }
+
+        assert!(received.starts_with(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"));
+        assert!(contains(&received, b"otlp-stand-in"));
+        assert!(contains(&received, b"pict-rs"));
+    }
+}
@@ -1,8 +1,22 @@
-use crate::{config::Format, error::UploadError, to_ext, validate::validate_image};
+use crate::{
+    concurrent_processor,
+    config::Format,
+    error::UploadError,
+    metrics,
+    processor::{build_path, process_image, ProcessChain},
+    signature::constant_time_eq,
+    store::{to_local_file, BytesStream, Store},
+    to_ext,
+    validate::{image_info, ptos, validate_image, ImageLimits},
+};
 use actix_web::web;
 use futures::stream::{Stream, StreamExt, TryStreamExt};
 use sha2::Digest;
-use std::{path::PathBuf, pin::Pin, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+};
 use tracing::{debug, error, info, instrument, warn, Span};
 
 #[derive(Clone)]
@@ -12,10 +26,14 @@ pub struct UploadManager {
 
 struct UploadManagerInner {
     format: Option<Format>,
+    limits: ImageLimits,
+    delete_token_length: usize,
     hasher: sha2::Sha256,
     image_dir: PathBuf,
+    store: Arc<dyn Store>,
     alias_tree: sled::Tree,
     filename_tree: sled::Tree,
+    details_tree: sled::Tree,
     db: sled::Db,
 }
 
@@ -27,6 +45,14 @@ impl std::fmt::Debug for UploadManager {
 
 type UploadStream<E> = Pin<Box<dyn Stream<Item = Result<bytes::Bytes, E>>>>;
 
+// The length of a sha256 hash, which prefixes every key in the main tree
+const HASH_LEN: usize = 32;
+
+const MIGRATE_DESTINATION: &[u8] = b"destination";
+
+const HASHED_DELETE_TOKENS: &[u8] = b"hashed-delete-tokens";
+const TOKEN_HASH_PREFIX: &str = "sha256";
+
 struct FilenameIVec {
     inner: sled::IVec,
 }
@@ -59,6 +85,20 @@ impl std::fmt::Debug for Hash {
     }
 }
 
+/// What pict-rs knows about a stored original or variant
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub(crate) struct Details {
+    width: usize,
+    height: usize,
+    content_type: String,
+    size: u64,
+    frames: usize,
+    // When the original was uploaded or the variant generated, which isn't known for files
+    // stored before details were recorded
+    created_at: Option<String>,
+    hash: String,
+}
+
 enum Dup {
     Exists,
     New,
@@ -74,38 +114,283 @@ impl Dup {
 }
 
 impl UploadManager {
-    /// Get the image directory
-    pub(crate) fn image_dir(&self) -> PathBuf {
-        self.inner.image_dir.clone()
+    /// Set the stored totals reported in metrics, which are kept up to date from then on
+    ///
+    /// This reads every recorded file, so it should only happen once at startup
+    #[instrument(skip(self))]
+    pub(crate) async fn seed_metrics(&self) -> Result<(), UploadError> {
+        let fname_tree = self.inner.filename_tree.clone();
+        let details_tree = self.inner.details_tree.clone();
+
+        let (originals, bytes) = web::block(move || {
+            let mut bytes = 0;
+            for res in details_tree.iter().values() {
+                if let Ok(details) = serde_json::from_slice::<Details>(&res?) {
+                    bytes += details.size;
+                }
+            }
+
+            Ok((fname_tree.len(), bytes)) as Result<_, UploadError>
+        })
+        .await?;
+
+        debug!("{} originals and {} bytes stored", originals, bytes);
+        metrics::set_stored(originals, bytes);
+        Ok(())
+    }
+
+    /// Make sure the database, the store and the temporary directory can all be written to
+    #[instrument(skip(self))]
+    pub(crate) async fn check_ready(&self) -> Result<(), UploadError> {
+        // Concurrent checks each get their own key, so they can't remove each other's
+        let key = format!("readyz-{}", uuid::Uuid::new_v4());
+
+        let db = self.inner.db.clone();
+        let db_key = key.clone();
+        web::block(move || {
+            let tree = db.open_tree("readyz")?;
+            tree.insert(db_key.as_bytes(), b"ok".to_vec())?;
+            tree.remove(db_key.as_bytes())?;
+            db.flush()?;
+            Ok(()) as Result<(), UploadError>
+        })
+        .await?;
+
+        debug!("Checking store");
+        self.inner
+            .store
+            .save_bytes(bytes::Bytes::from_static(b"ok"), &key)
+            .await?;
+        self.inner.store.remove(&key).await?;
+
+        debug!("Checking temporary directory");
+        let tmpfile = tmp_file();
+        web::block(move || {
+            if let Some(parent) = tmpfile.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&tmpfile, b"ok")?;
+            std::fs::remove_file(&tmpfile)?;
+            Ok(()) as Result<(), UploadError>
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get the details of an original or variant, reading them from the store if they weren't
+    /// recorded when it was saved
+    #[instrument(skip(self))]
+    pub(crate) async fn details(&self, key: &str) -> Result<Details, UploadError> {
+        if let Some(details) = self.stored_details(key).await? {
+            return Ok(details);
+        }
+
+        debug!("Details missing, reading {} from the store", key);
+        let file = to_local_file(self.store(), key).await?;
+        let hash = self.hash(file.path()).await?;
+        let details = compute_details(file.path(), &hash.inner, None).await?;
+        drop(file);
+
+        self.save_details(key, &details).await?;
+
+        Ok(details)
+    }
+
+    /// Get the details of a variant from its bytes, recording them if they're new
+    #[instrument(skip(self, bytes))]
+    pub(crate) async fn variant_details(
+        &self,
+        key: &str,
+        bytes: bytes::Bytes,
+    ) -> Result<Details, UploadError> {
+        if let Some(details) = self.stored_details(key).await? {
+            return Ok(details);
+        }
+
+        let hash = sha256(bytes.clone()).await?;
+
+        let tmpfile = tmp_file();
+        let tmpfile2 = tmpfile.clone();
+        web::block(move || {
+            if let Some(parent) = tmpfile2.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&tmpfile2, &bytes)
+        })
+        .await?;
+
+        let created_at = chrono::Utc::now().to_rfc3339();
+        let res = compute_details(tmpfile.clone(), &hash, Some(created_at)).await;
+        let _ = actix_fs::remove_file(tmpfile).await;
+        let details = res?;
+
+        self.save_details(key, &details).await?;
+
+        Ok(details)
+    }
+
+    async fn stored_details(&self, key: &str) -> Result<Option<Details>, UploadError> {
+        let tree = self.inner.details_tree.clone();
+        let key = key.to_owned();
+        let details = web::block(move || tree.get(key.as_bytes())).await?;
+
+        match details {
+            Some(details) => Ok(Some(serde_json::from_slice(&details)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Keep whichever details were recorded first, in case two requests work them out at once
+    async fn save_details(&self, key: &str, details: &Details) -> Result<(), UploadError> {
+        let tree = self.inner.details_tree.clone();
+        let key = key.to_owned();
+        let size = details.size;
+        let details = serde_json::to_vec(details)?;
+
+        debug!("Saving details");
+        // A conflict means another request already saved them
+        let res = web::block(move || {
+            tree.compare_and_swap(key.as_bytes(), None as Option<sled::IVec>, Some(details))
+        })
+        .await?;
+
+        if res.is_ok() {
+            metrics::details_stored(size);
+        }
+
+        Ok(())
+    }
+
+    async fn remove_details(&self, key: String) -> Result<(), UploadError> {
+        let tree = self.inner.details_tree.clone();
+        let removed = web::block(move || tree.remove(key.as_bytes())).await?;
+
+        if let Some(details) = removed {
+            if let Ok(details) = serde_json::from_slice::<Details>(&details) {
+                metrics::details_removed(details.size);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the store holding originals and variants
+    pub(crate) fn store(&self) -> &dyn Store {
+        &*self.inner.store
     }
 
     /// Create a new UploadManager
     pub(crate) async fn new(
         mut root_dir: PathBuf,
         format: Option<Format>,
+        limits: ImageLimits,
+        delete_token_length: usize,
+        store: Arc<dyn Store>,
     ) -> Result<Self, UploadError> {
         let mut sled_dir = root_dir.clone();
         sled_dir.push("db");
         // sled automatically creates it's own directories
         let db = web::block(move || sled::open(sled_dir)).await?;
 
-        root_dir.push("files");
+        let alias_tree = db.open_tree("alias")?;
+        let migrations = db.open_tree("migrations")?;
+        let tree = alias_tree.clone();
+        web::block(move || hash_legacy_tokens(&migrations, &tree)).await?;
 
-        // Ensure file dir exists
-        actix_fs::create_dir_all(root_dir.clone()).await?;
+        // Variants generated before stores existed were recorded with their full path in here
+        root_dir.push("files");
 
         Ok(UploadManager {
             inner: Arc::new(UploadManagerInner {
                 format,
+                limits,
+                delete_token_length,
                 hasher: sha2::Sha256::new(),
                 image_dir: root_dir,
-                alias_tree: db.open_tree("alias")?,
+                store,
+                alias_tree,
                 filename_tree: db.open_tree("filename")?,
+                details_tree: db.open_tree("details")?,
                 db,
             }),
         })
     }
 
+    /// Generate variants of an alias in the background
+    pub(crate) fn generate_variants(&self, alias: String, chains: Vec<ProcessChain>) {
+        if chains.is_empty() {
+            return;
+        }
+
+        let this = self.clone();
+        debug!("Spawning variant generation task");
+        let span = Span::current();
+        actix_rt::spawn(async move {
+            let entered = span.enter();
+            for chain in chains {
+                if let Err(e) = this.generate_variant(&alias, chain).await {
+                    error!("Error generating variant, {}", e);
+                }
+            }
+            drop(entered);
+        });
+    }
+
+    // Process and save a single variant, unless it already exists
+    #[instrument(skip(self))]
+    async fn generate_variant(&self, alias: &str, chain: ProcessChain) -> Result<(), UploadError> {
+        let name = self.from_alias(alias.to_owned()).await?;
+        let path = build_path(PathBuf::new(), &chain, name.clone());
+        let key = ptos(&path)?;
+
+        if self.store().exists(&key).await? {
+            debug!("Variant already exists");
+            return Ok(());
+        }
+
+        let this = self.clone();
+        let span = Span::current();
+        concurrent_processor::process(
+            key,
+            || async {
+                let original = to_local_file(self.store(), &name).await?;
+                process_image(original.path(), chain).await
+            },
+            move |bytes| async move {
+                let entered = span.enter();
+                if let Err(e) = this.save_variant(path, bytes).await {
+                    error!("Error saving variant, {}", e);
+                }
+                drop(entered);
+            },
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record a generated variant and write it to the store
+    #[instrument(skip(self, bytes))]
+    pub(crate) async fn save_variant(
+        &self,
+        path: PathBuf,
+        bytes: bytes::Bytes,
+    ) -> Result<(), UploadError> {
+        let key = ptos(&path)?;
+        let len = bytes.len() as u64;
+
+        self.store_variant(path).await?;
+        self.store().save_bytes(bytes.clone(), &key).await?;
+        metrics::bytes_stored(len);
+
+        if let Err(e) = self.variant_details(&key, bytes).await {
+            warn!("Couldn't read details of {}, {}", key, e);
+        }
+
+        Ok(())
+    }
+
     /// Store the path to a generated image variant so we can easily clean it up later
     #[instrument(skip(self))]
     pub(crate) async fn store_variant(&self, path: PathBuf) -> Result<(), UploadError> {
@@ -153,7 +438,7 @@ impl UploadManager {
                     .ok_or(trans_err(UploadError::MissingAlias))?;
 
                 // Bail if invalid token
-                if existing_token != token {
+                if !verify_token(&existing_token, &token) {
                     warn!("Invalid delete token");
                     return Err(trans_err(UploadError::InvalidToken));
                 }
@@ -225,39 +510,25 @@ impl UploadManager {
         Ok(())
     }
 
-    /// Generate a delete token for an alias
+    /// Generate a delete token for an alias, replacing any previous token
+    ///
+    /// Only a hash of the token is stored, so this is the only time it can be retrieved
     #[instrument(skip(self))]
     pub(crate) async fn delete_token(&self, alias: String) -> Result<String, UploadError> {
         debug!("Generating delete token");
         use rand::distributions::{Alphanumeric, Distribution};
         let rng = rand::thread_rng();
-        let s: String = Alphanumeric.sample_iter(rng).take(10).collect();
-        let delete_token = s.clone();
+        let delete_token: String = Alphanumeric
+            .sample_iter(rng)
+            .take(self.inner.delete_token_length)
+            .collect();
 
         debug!("Saving delete token");
         let alias_tree = self.inner.alias_tree.clone();
         let key = delete_key(&alias);
-        let res = web::block(move || {
-            alias_tree.compare_and_swap(
-                key.as_bytes(),
-                None as Option<sled::IVec>,
-                Some(s.as_bytes()),
-            )
-        })
-        .await?;
-
-        if let Err(sled::CompareAndSwapError {
-            current: Some(ivec),
-            ..
-        }) = res
-        {
-            let s = String::from_utf8(ivec.to_vec())?;
+        let hashed = hash_token(&delete_token);
+        web::block(move || alias_tree.insert(key.as_bytes(), hashed.as_bytes())).await?;
 
-            debug!("Returning existing delete token, {}", s);
-            return Ok(s);
-        }
-
-        debug!("Returning new delete token, {}", delete_token);
         Ok(delete_token)
     }
 
@@ -270,6 +541,24 @@ impl UploadManager {
         validate: bool,
         stream: UploadStream<E>,
     ) -> Result<String, UploadError>
+    where
+        UploadError: From<E>,
+        E: Unpin,
+    {
+        let res = self
+            .import_inner(alias, content_type, validate, stream)
+            .await;
+        metrics::upload(res.as_ref().map(|(_, content_type)| content_type));
+        res.map(|(alias, _)| alias)
+    }
+
+    async fn import_inner<E>(
+        &self,
+        alias: String,
+        content_type: mime::Mime,
+        validate: bool,
+        stream: UploadStream<E>,
+    ) -> Result<(String, mime::Mime), UploadError>
     where
         UploadError: From<E>,
         E: Unpin,
@@ -282,7 +571,8 @@ impl UploadManager {
         let content_type = if validate {
             debug!("Validating image");
             let format = self.inner.format.clone();
-            validate_image(tmpfile.clone(), format).await?
+            let limits = self.inner.limits.clone();
+            validate_image(tmpfile.clone(), format, limits).await?
         } else {
             content_type
         };
@@ -297,15 +587,29 @@ impl UploadManager {
         self.add_existing_alias(&hash, &alias).await?;
 
         debug!("Saving file");
-        self.save_upload(tmpfile, hash, content_type).await?;
+        self.save_upload(tmpfile, hash, content_type.clone())
+            .await?;
 
         // Return alias to file
-        Ok(alias)
+        Ok((alias, content_type))
     }
 
     /// Upload the file, discarding bytes if it's already present, or saving if it's new
     #[instrument(skip(self, stream))]
     pub(crate) async fn upload<E>(&self, stream: UploadStream<E>) -> Result<String, UploadError>
+    where
+        UploadError: From<E>,
+        E: Unpin,
+    {
+        let res = self.upload_inner(stream).await;
+        metrics::upload(res.as_ref().map(|(_, content_type)| content_type));
+        res.map(|(alias, _)| alias)
+    }
+
+    async fn upload_inner<E>(
+        &self,
+        stream: UploadStream<E>,
+    ) -> Result<(String, mime::Mime), UploadError>
     where
         UploadError: From<E>,
         E: Unpin,
@@ -318,7 +622,8 @@ impl UploadManager {
         // -- VALIDATE IMAGE --
         debug!("Validating image");
         let format = self.inner.format.clone();
-        let content_type = validate_image(tmpfile.clone(), format).await?;
+        let limits = self.inner.limits.clone();
+        let content_type = validate_image(tmpfile.clone(), format, limits).await?;
 
         // -- DUPLICATE CHECKS --
 
@@ -330,10 +635,124 @@ impl UploadManager {
         let alias = self.add_alias(&hash, content_type.clone()).await?;
 
         debug!("Saving file");
-        self.save_upload(tmpfile, hash, content_type).await?;
+        self.save_upload(tmpfile, hash, content_type.clone())
+            .await?;
 
         // Return alias to file
-        Ok(alias)
+        Ok((alias, content_type))
+    }
+
+    /// Copy every original and variant from this manager's store into another store
+    ///
+    /// Stored variant paths are rewritten as store keys along the way. Progress is recorded per
+    /// hash, so running this again after an interruption skips files that were already copied
+    #[instrument(skip(self, to))]
+    pub(crate) async fn migrate_store(
+        &self,
+        to: &dyn Store,
+        destination: String,
+    ) -> Result<(), UploadError> {
+        let progress = self.inner.db.open_tree("migrate")?;
+
+        // Progress only counts for the destination it was recorded against
+        let tree = progress.clone();
+        let dest = destination.clone();
+        web::block(move || {
+            if tree.get(MIGRATE_DESTINATION)?.as_deref() != Some(dest.as_bytes()) {
+                tree.clear()?;
+                tree.insert(MIGRATE_DESTINATION, dest.as_bytes())?;
+            }
+            Ok(()) as Result<(), UploadError>
+        })
+        .await?;
+
+        let db = self.inner.db.clone();
+        debug!("Collecting hashes");
+        let files = web::block(move || {
+            let mut files = Vec::new();
+            for res in db.iter() {
+                let (hash, filename) = res?;
+                // Everything else in this tree is a hash followed by a separator and more data
+                if hash.len() == HASH_LEN {
+                    files.push((hash, filename));
+                }
+            }
+
+            Ok(files) as Result<Vec<(sled::IVec, sled::IVec)>, UploadError>
+        })
+        .await?;
+
+        let total = files.len();
+        info!("Migrating {} files to {}", total, destination);
+
+        for (index, (hash, filename)) in files.into_iter().enumerate() {
+            let tree = progress.clone();
+            let hash2 = hash.clone();
+            if web::block(move || tree.contains_key(hash2)).await? {
+                debug!(
+                    "Skipping already migrated hash {:?}",
+                    Hash::new(hash.to_vec())
+                );
+                continue;
+            }
+
+            let filename = String::from_utf8(filename.to_vec())?;
+            info!("Migrating {} ({}/{})", filename, index + 1, total);
+            copy_verified(self.store(), to, &filename, Some(&hash[..])).await?;
+
+            self.migrate_variants(to, &hash).await?;
+
+            let tree = progress.clone();
+            web::block(move || tree.insert(hash, &[] as &[u8])).await?;
+        }
+
+        let tree = progress.clone();
+        web::block(move || tree.clear()).await?;
+        info!("Migrated {} files to {}", total, destination);
+
+        Ok(())
+    }
+
+    // Copy the variants for a hash, rewriting their records to hold store keys
+    async fn migrate_variants(&self, to: &dyn Store, hash: &[u8]) -> Result<(), UploadError> {
+        let (start, end) = variant_key_bounds(hash);
+        let db = self.inner.db.clone();
+        let variants = web::block(move || {
+            let mut variants = Vec::new();
+            for res in db.range(start..end) {
+                variants.push(res?);
+            }
+
+            Ok(variants) as Result<Vec<(sled::IVec, sled::IVec)>, UploadError>
+        })
+        .await?;
+
+        for (variant_key_ivec, path) in variants {
+            let path_string = String::from_utf8(path.to_vec())?;
+            let key = variant_store_key(&self.inner.image_dir, &path_string)?;
+
+            let res = copy_verified(self.store(), to, &key, None).await;
+
+            let db = self.inner.db.clone();
+            let new_key = variant_key(hash, &key);
+            if let Err(e) = res {
+                // Variants are regenerated on request, so a missing one isn't worth aborting for
+                warn!("Dropping variant {}, {}", key, e);
+                web::block(move || db.remove(variant_key_ivec)).await?;
+                continue;
+            }
+
+            if key != path_string {
+                debug!("Rewriting variant path {} to {}", path_string, key);
+                web::block(move || {
+                    db.remove(variant_key_ivec)?;
+                    db.insert(new_key, key.as_bytes())
+                })
+                .await?;
+            }
+        }
+
+        Ok(())
     }
 
     /// Fetch the real on-disk filename given an alias
@@ -356,18 +775,19 @@ impl UploadManager {
         Ok(filename)
     }
 
-    // Find image variants and remove them from the DB and the disk
+    // Find image variants and remove them from the DB and the store
     #[instrument(skip(self))]
     async fn cleanup_files(&self, filename: FilenameIVec) -> Result<(), UploadError> {
         let filename = filename.inner;
-        let mut path = self.image_dir();
         let fname = String::from_utf8(filename.to_vec())?;
-        path.push(fname);
 
         let mut errors = Vec::new();
-        debug!("Deleting {:?}", path);
-        if let Err(e) = actix_fs::remove_file(path).await {
-            errors.push(e.into());
+        debug!("Deleting {:?}", fname);
+        if let Err(e) = self.store().remove(&fname).await {
+            errors.push(e);
+        }
+        if let Err(e) = self.remove_details(fname.clone()).await {
+            errors.push(e);
         }
 
         let fname_tree = self.inner.filename_tree.clone();
@@ -375,6 +795,7 @@ impl UploadManager {
         let hash = web::block(move || fname_tree.remove(filename))
             .await?
             .ok_or(UploadError::MissingFile)?;
+        metrics::original_removed();
 
         let (start, end) = variant_key_bounds(&hash);
         let db = self.inner.db.clone();
@@ -395,7 +816,7 @@ impl UploadManager {
             let db = self.inner.db.clone();
             if let Some(path) = web::block(move || db.remove(key)).await? {
                 debug!("Deleting {:?}", String::from_utf8(path.to_vec()));
-                if let Err(e) = remove_path(path).await {
+                if let Err(e) = self.remove_variant(path).await {
                     errors.push(e);
                 }
             }
@@ -414,6 +835,7 @@ impl UploadManager {
         hash: Hash,
         content_type: mime::Mime,
     ) -> Result<(), UploadError> {
+        let hash_bytes = hash.inner.clone();
         let (dup, name) = self.check_duplicate(hash, content_type).await?;
 
         // bail early with alias to existing file if this is a duplicate
@@ -422,15 +844,29 @@ impl UploadManager {
             return Ok(());
         }
 
-        // -- WRITE NEW FILE --
-        let mut real_path = self.image_dir();
-        real_path.push(name);
+        let created_at = chrono::Utc::now().to_rfc3339();
+        match compute_details(tmpfile.clone(), &hash_bytes, Some(created_at)).await {
+            Ok(details) => self.save_details(&name, &details).await?,
+            // They'll be read from the store on the first request for them instead
+            Err(e) => warn!("Couldn't read details of {}, {}", name, e),
+        }
 
-        safe_move_file(tmpfile, real_path).await?;
+        // -- WRITE NEW FILE --
+        let tmpfile2 = tmpfile.clone();
+        let len = web::block(move || tmpfile2.metadata().map(|m| m.len())).await?;
+        self.store().save_file(tmpfile, &name).await?;
+        metrics::bytes_stored(len);
 
         Ok(())
     }
 
+    async fn remove_variant(&self, path: sled::IVec) -> Result<(), UploadError> {
+        let path_string = String::from_utf8(path.to_vec())?;
+        let key = variant_store_key(&self.inner.image_dir, &path_string)?;
+        self.store().remove(&key).await?;
+        self.remove_details(key).await
+    }
+
     // produce a sh256sum of the uploaded file
     async fn hash(&self, tmpfile: PathBuf) -> Result<Hash, UploadError> {
         let mut hasher = self.inner.hasher.clone();
@@ -482,6 +918,7 @@ impl UploadManager {
         {
             let name = String::from_utf8(ivec.to_vec())?;
             debug!("Filename exists for hash, {}", name);
+            metrics::duplicate();
             return Ok((Dup::Exists, name));
         }
 
@@ -489,6 +926,7 @@ impl UploadManager {
         let filename2 = filename.clone();
         debug!("Saving filename -> hash relation");
         web::block(move || fname_tree.insert(filename2, hash.inner)).await?;
+        metrics::original_stored();
 
         Ok((Dup::New, filename))
     }
@@ -496,25 +934,18 @@ impl UploadManager {
     // generate a short filename that isn't already in-use
     #[instrument(skip(self, content_type))]
     async fn next_file(&self, content_type: mime::Mime) -> Result<String, UploadError> {
-        let image_dir = self.image_dir();
         use rand::distributions::{Alphanumeric, Distribution};
         let mut limit: usize = 10;
         let rng = rand::thread_rng();
         loop {
             debug!("Filename generation loop");
-            let mut path = image_dir.clone();
             let s: String = Alphanumeric.sample_iter(rng).take(limit).collect();
 
             let filename = file_name(s, content_type.clone());
 
-            path.push(filename.clone());
-
-            if let Err(e) = actix_fs::metadata(path).await {
-                if e.kind() == Some(std::io::ErrorKind::NotFound) {
-                    debug!("Generated unused filename {}", filename);
-                    return Ok(filename);
-                }
-                return Err(e.into());
+            if !self.store().exists(&filename).await? {
+                debug!("Generated unused filename {}", filename);
+                return Ok(filename);
             }
 
             debug!("Filename exists, trying again");
@@ -651,28 +1082,6 @@ pub(crate) fn tmp_file() -> PathBuf {
     path
 }
 
-#[instrument]
-async fn safe_move_file(from: PathBuf, to: PathBuf) -> Result<(), UploadError> {
-    if let Some(path) = to.parent() {
-        debug!("Creating directory {:?}", path);
-        actix_fs::create_dir_all(path.to_owned()).await?;
-    }
-
-    debug!("Checking if {:?} already exists", to);
-    if let Err(e) = actix_fs::metadata(to.clone()).await {
-        if e.kind() != Some(std::io::ErrorKind::NotFound) {
-            return Err(e.into());
-        }
-    } else {
-        return Err(UploadError::FileExists);
-    }
-
-    debug!("Moving {:?} to {:?}", from, to);
-    actix_fs::copy(from.clone(), to).await?;
-    actix_fs::remove_file(from).await?;
-    Ok(())
-}
-
 #[instrument(skip(stream))]
 async fn safe_save_stream<E>(to: PathBuf, stream: UploadStream<E>) -> Result<(), UploadError>
 where
@@ -700,10 +1109,96 @@ where
     Ok(())
 }
 
-async fn remove_path(path: sled::IVec) -> Result<(), UploadError> {
-    let path_string = String::from_utf8(path.to_vec())?;
-    actix_fs::remove_file(path_string).await?;
-    Ok(())
+// Copy a file between stores, checking both the source and the copy against the expected hash
+#[instrument(skip(from, to, expected))]
+async fn copy_verified(
+    from: &dyn Store,
+    to: &dyn Store,
+    key: &str,
+    expected: Option<&[u8]>,
+) -> Result<(), UploadError> {
+    let bytes = read_all(from.to_stream(key).await?).await?;
+    let hash = sha256(bytes.clone()).await?;
+
+    if let Some(expected) = expected {
+        if hash != expected {
+            return Err(UploadError::ChecksumMismatch(key.to_owned()));
+        }
+    }
+
+    // A previous, interrupted run may have left a partial copy behind, so retry once
+    for attempt in 0..2 {
+        to.save_bytes(bytes.clone(), key).await?;
+
+        let written = read_all(to.to_stream(key).await?).await?;
+        if sha256(written).await? == hash {
+            return Ok(());
+        }
+
+        warn!(
+            "Copy of {} is corrupt, removing (attempt {})",
+            key,
+            attempt + 1
+        );
+        to.remove(key).await?;
+    }
+
+    Err(UploadError::ChecksumMismatch(key.to_owned()))
+}
+
+async fn compute_details(
+    file: PathBuf,
+    hash: &[u8],
+    created_at: Option<String>,
+) -> Result<Details, UploadError> {
+    let file2 = file.clone();
+    let size = web::block(move || file2.metadata().map(|m| m.len())).await?;
+    let info = image_info(file).await?;
+
+    Ok(Details {
+        width: info.width,
+        height: info.height,
+        content_type: info.content_type.to_string(),
+        size,
+        frames: info.frames,
+        created_at,
+        hash: hex::encode(hash),
+    })
+}
+
+async fn read_all(mut stream: BytesStream) -> Result<bytes::Bytes, UploadError> {
+    let mut bytes = bytes::BytesMut::new();
+    while let Some(res) = stream.next().await {
+        bytes.extend_from_slice(&res?);
+    }
+    Ok(bytes.freeze())
+}
+
+async fn sha256(bytes: bytes::Bytes) -> Result<Vec<u8>, UploadError> {
+    let hash =
+        web::block(move || Ok(sha2::Sha256::digest(&bytes).to_vec()) as Result<_, UploadError>)
+            .await?;
+
+    Ok(hash)
+}
+
+// Turn a stored variant path into a store key, accounting for full paths saved by older versions
+fn variant_store_key(image_dir: &Path, path_string: &str) -> Result<String, UploadError> {
+    let path = Path::new(path_string);
+
+    let relative = if path.is_absolute() {
+        path.strip_prefix(image_dir)
+            .map_err(|_| UploadError::Path)?
+    } else {
+        path
+    };
+
+    let segments = relative
+        .iter()
+        .map(|s| s.to_str().ok_or(UploadError::Path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(segments.join("/"))
 }
 
 fn trans_err(e: UploadError) -> sled::transaction::ConflictableTransactionError<UploadError> {
@@ -741,6 +1236,67 @@ fn delete_key(alias: &str) -> String {
     format!("{}/delete", alias)
 }
 
+// Tokens are stored as `sha256:{salt}:{digest}`, hex encoded
+fn hash_token(token: &str) -> String {
+    use rand::Rng;
+    let salt: [u8; 16] = rand::thread_rng().gen();
+
+    format!(
+        "{}:{}:{}",
+        TOKEN_HASH_PREFIX,
+        hex::encode(&salt),
+        hex::encode(token_digest(&salt, token))
+    )
+}
+
+fn token_digest(salt: &[u8], token: &str) -> Vec<u8> {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(salt);
+    hasher.update(token.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+fn verify_token(stored: &[u8], token: &str) -> bool {
+    let stored = match std::str::from_utf8(stored) {
+        Ok(stored) => stored,
+        Err(_) => return false,
+    };
+
+    let mut parts = stored.splitn(3, ':');
+    let prefix = parts.next();
+    let salt = parts.next().and_then(|salt| hex::decode(salt).ok());
+    let digest = parts.next().and_then(|digest| hex::decode(digest).ok());
+
+    match (prefix, salt, digest) {
+        (Some(TOKEN_HASH_PREFIX), Some(salt), Some(digest)) => {
+            constant_time_eq(&digest, &token_digest(&salt, token))
+        }
+        _ => false,
+    }
+}
+
+// Delete tokens used to be stored as they were handed out
+fn hash_legacy_tokens(migrations: &sled::Tree, alias_tree: &sled::Tree) -> Result<(), UploadError> {
+    if migrations.contains_key(HASHED_DELETE_TOKENS)? {
+        return Ok(());
+    }
+
+    info!("Hashing existing delete tokens");
+    let prefix = format!("{}:", TOKEN_HASH_PREFIX);
+
+    for res in alias_tree.iter() {
+        let (key, value) = res?;
+
+        if key.ends_with(b"/delete") && !value.starts_with(prefix.as_bytes()) {
+            let token = String::from_utf8(value.to_vec())?;
+            alias_tree.insert(key, hash_token(&token).as_bytes())?;
+        }
+    }
+
+    migrations.insert(HASHED_DELETE_TOKENS, &[] as &[u8])?;
+    Ok(())
+}
+
 fn variant_key(hash: &[u8], path: &str) -> Vec<u8> {
     let mut key = hash.to_vec();
     key.extend(&[2]);
@@ -757,3 +1313,85 @@ fn variant_key_bounds(hash: &[u8]) -> (Vec<u8>, Vec<u8>) {
 
     (start, end)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trees() -> (sled::Tree, sled::Tree) {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let migrations = db.open_tree("migrations").unwrap();
+        let alias_tree = db.open_tree("alias").unwrap();
+
+        (migrations, alias_tree)
+    }
+
+    fn stored_token(alias_tree: &sled::Tree, alias: &str) -> sled::IVec {
+        alias_tree.get(delete_key(alias)).unwrap().unwrap()
+    }
+
+    #[test]
+    fn verifies_hashed_tokens() {
+        let stored = hash_token("token");
+
+        assert!(stored.starts_with("sha256:"));
+        assert!(!stored.contains("token"));
+        assert!(verify_token(stored.as_bytes(), "token"));
+        assert!(!verify_token(stored.as_bytes(), "tokem"));
+        assert!(!verify_token(stored.as_bytes(), ""));
+    }
+
+    #[test]
+    fn salts_each_token() {
+        assert_ne!(hash_token("token"), hash_token("token"));
+    }
+
+    #[test]
+    fn rejects_plaintext_tokens() {
+        assert!(!verify_token(b"token", "token"));
+        assert!(!verify_token(b"sha256:zz:zz", "token"));
+    }
+
+    #[test]
+    fn hashes_legacy_tokens() {
+        let (migrations, alias_tree) = trees();
+        alias_tree
+            .insert(delete_key("a.png"), b"legacy".as_ref())
+            .unwrap();
+        alias_tree
+            .insert(alias_id_key("a.png"), b"id".as_ref())
+            .unwrap();
+
+        hash_legacy_tokens(&migrations, &alias_tree).unwrap();
+
+        let stored = stored_token(&alias_tree, "a.png");
+        assert!(stored.starts_with(b"sha256:"));
+        assert!(verify_token(&stored, "legacy"));
+        assert!(!verify_token(&stored, "other"));
+        // Only delete tokens are touched
+        assert_eq!(
+            alias_tree.get(alias_id_key("a.png")).unwrap().unwrap(),
+            b"id".as_ref()
+        );
+    }
+
+    #[test]
+    fn hashes_legacy_tokens_once() {
+        let (migrations, alias_tree) = trees();
+        alias_tree
+            .insert(delete_key("a.png"), b"legacy".as_ref())
+            .unwrap();
+
+        hash_legacy_tokens(&migrations, &alias_tree).unwrap();
+        let first = stored_token(&alias_tree, "a.png");
+
+        hash_legacy_tokens(&migrations, &alias_tree).unwrap();
+        assert_eq!(stored_token(&alias_tree, "a.png"), first);
+
+        // Hashed tokens are left alone even if the migration runs again
+        migrations.remove(HASHED_DELETE_TOKENS).unwrap();
+        hash_legacy_tokens(&migrations, &alias_tree).unwrap();
+        assert_eq!(stored_token(&alias_tree, "a.png"), first);
+        assert!(verify_token(&first, "legacy"));
+    }
+}
@@ -1,13 +1,16 @@
-use crate::{config::Format, error::UploadError, upload_manager::tmp_file};
-use actix_web::web;
+use crate::{
+    config::Format, error::UploadError, magick_pool, metrics, sandbox, upload_manager::tmp_file,
+};
 use magick_rust::MagickWand;
 use rexiv2::{MediaType, Metadata};
 use std::{
+    borrow::Cow,
     fs::File,
     io::{BufReader, BufWriter, Write},
     path::PathBuf,
+    time::Instant,
 };
-use tracing::{debug, error, instrument, trace, warn, Span};
+use tracing::{debug, error, instrument, trace, warn};
 
 pub(crate) trait Op {
     fn op<F, T>(&self, f: F) -> Result<T, UploadError>
@@ -19,6 +22,20 @@ pub(crate) trait Op {
         F: Fn(&mut Self) -> Result<T, &'static str>;
 }
 
+// ImageMagick reports exceeding a configured resource limit like any other exception
+fn wand_error(msg: String) -> UploadError {
+    let lower = msg.to_lowercase();
+
+    if lower.contains("resources exhausted")
+        || lower.contains("limit exceeded")
+        || lower.contains("exceeds limit")
+    {
+        UploadError::ResourceLimit(msg)
+    } else {
+        UploadError::Wand(msg)
+    }
+}
+
 impl Op for MagickWand {
     fn op<F, T>(&self, f: F) -> Result<T, UploadError>
     where
@@ -29,9 +46,9 @@ impl Op for MagickWand {
             Err(e) => {
                 if let Ok(e) = self.get_exception() {
                     error!("WandError: {}", e.0);
-                    Err(UploadError::Wand(e.0.to_owned()))
+                    Err(wand_error(e.0.to_owned()))
                 } else {
-                    Err(UploadError::Wand(e.to_owned()))
+                    Err(wand_error(e.to_owned()))
                 }
             }
         }
@@ -46,9 +63,9 @@ impl Op for MagickWand {
             Err(e) => {
                 if let Ok(e) = self.get_exception() {
                     error!("WandError: {}", e.0);
-                    Err(UploadError::Wand(e.0.to_owned()))
+                    Err(wand_error(e.0.to_owned()))
                 } else {
-                    Err(UploadError::Wand(e.to_owned()))
+                    Err(wand_error(e.to_owned()))
                 }
             }
         }
@@ -62,6 +79,43 @@ pub(crate) enum GifError {
 
     #[error("Error reading bytes")]
     Io(#[from] std::io::Error),
+
+    #[error("{0}")]
+    TooLarge(String),
+}
+
+/// Bounds on the size of uploaded images, checked before they're fully decoded
+#[derive(Clone, Debug)]
+pub(crate) struct ImageLimits {
+    pub(crate) max_width: usize,
+    pub(crate) max_height: usize,
+    pub(crate) max_area: usize,
+    pub(crate) max_frame_count: usize,
+}
+
+impl ImageLimits {
+    fn check(&self, width: usize, height: usize) -> Result<(), String> {
+        if width > self.max_width {
+            return Err(format!("width {} is larger than {}", width, self.max_width));
+        }
+
+        if height > self.max_height {
+            return Err(format!(
+                "height {} is larger than {}",
+                height, self.max_height
+            ));
+        }
+
+        let area = width.saturating_mul(height);
+        if area > self.max_area {
+            return Err(format!(
+                "{}x{} is more than {} pixels",
+                width, height, self.max_area
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 pub(crate) fn image_webp() -> mime::Mime {
@@ -72,6 +126,17 @@ pub(crate) fn ptos(p: &PathBuf) -> Result<String, UploadError> {
     Ok(p.to_str().ok_or(UploadError::Path)?.to_owned())
 }
 
+// Read only the image's headers, so oversized images are rejected before allocating pixels
+fn check_dimensions(file: &str, limits: &ImageLimits) -> Result<(), UploadError> {
+    let wand = MagickWand::new();
+    debug!("pinging");
+    wand.op(|w| w.ping_image(file))?;
+
+    limits
+        .check(wand.get_image_width(), wand.get_image_height())
+        .map_err(UploadError::ImageTooLarge)
+}
+
 fn validate_format(file: &str, format: &str) -> Result<(), UploadError> {
     let wand = MagickWand::new();
     debug!("reading");
@@ -84,101 +149,182 @@ fn validate_format(file: &str, format: &str) -> Result<(), UploadError> {
     Ok(())
 }
 
+/// The format, dimensions and frame count of an image
+#[derive(Debug)]
+pub(crate) struct ImageInfo {
+    pub(crate) content_type: mime::Mime,
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    pub(crate) frames: usize,
+}
+
+/// Read an image's format, dimensions and frame count without decoding its pixels
+#[instrument]
+pub(crate) async fn image_info(file: PathBuf) -> Result<ImageInfo, UploadError> {
+    if crate::CONFIG.sandbox() {
+        magick_pool::run(move || sandbox::info(&file)).await
+    } else {
+        magick_pool::run(move || read_info(&file)).await
+    }
+}
+
+/// Read an image's format, dimensions and frame count in this process
+pub(crate) fn read_info(file: &PathBuf) -> Result<ImageInfo, UploadError> {
+    let file_str = ptos(file)?;
+
+    let wand = MagickWand::new();
+    debug!("pinging");
+    wand.op(|w| w.ping_image(&file_str))?;
+
+    let content_type = match wand.op(|w| w.get_image_format())?.as_str() {
+        "JPEG" => mime::IMAGE_JPEG,
+        "PNG" => mime::IMAGE_PNG,
+        "GIF" => return Ok(gif_info(file)?),
+        "WEBP" => image_webp(),
+        _ => return Err(UploadError::UnsupportedFormat),
+    };
+
+    Ok(ImageInfo {
+        content_type,
+        width: wand.get_image_width(),
+        height: wand.get_image_height(),
+        frames: 1,
+    })
+}
+
+fn gif_info(file: &PathBuf) -> Result<ImageInfo, GifError> {
+    use gif::SetParameter;
+
+    let mut decoder = gif::Decoder::new(BufReader::new(File::open(file)?));
+
+    decoder.set(gif::ColorOutput::Indexed);
+
+    let mut reader = decoder.read_info()?;
+
+    let mut frames = 0;
+    while reader.next_frame_info()?.is_some() {
+        frames += 1;
+    }
+
+    Ok(ImageInfo {
+        content_type: mime::IMAGE_GIF,
+        width: reader.width().into(),
+        height: reader.height().into(),
+        frames,
+    })
+}
+
 // import & export image using the image crate
 #[instrument]
 pub(crate) async fn validate_image(
     tmpfile: PathBuf,
     prescribed_format: Option<Format>,
+    limits: ImageLimits,
 ) -> Result<mime::Mime, UploadError> {
-    let tmpfile_str = ptos(&tmpfile)?;
-    let span = Span::current();
+    let start = Instant::now();
 
-    let content_type = web::block(move || {
-        let entered = span.enter();
+    let res = if crate::CONFIG.sandbox() {
+        magick_pool::run(move || sandbox::validate(&tmpfile, prescribed_format, &limits)).await
+    } else {
+        magick_pool::run(move || validate_file(&tmpfile, prescribed_format, &limits)).await
+    };
 
-        let meta = Metadata::new_from_path(&tmpfile)?;
+    metrics::validated(start);
+    res
+}
 
-        let content_type = match (prescribed_format, meta.get_media_type()?) {
-            (_, MediaType::Gif) => {
-                let newfile = tmp_file();
-                validate_gif(&tmpfile, &newfile)?;
+/// Validate the file at the given path, rewriting it in place without metadata
+pub(crate) fn validate_file(
+    tmpfile: &PathBuf,
+    prescribed_format: Option<Format>,
+    limits: &ImageLimits,
+) -> Result<mime::Mime, UploadError> {
+    let tmpfile_str = ptos(tmpfile)?;
 
-                mime::IMAGE_GIF
-            }
-            (Some(Format::Jpeg), MediaType::Jpeg) | (None, MediaType::Jpeg) => {
-                validate_format(&tmpfile_str, "JPEG")?;
+    let meta = Metadata::new_from_path(tmpfile)?;
+    let media_type = meta.get_media_type()?;
 
-                meta.clear();
-                meta.save_to_file(&tmpfile)?;
+    // GIFs are checked frame by frame while they're transmuted
+    if !matches!(media_type, MediaType::Gif) {
+        check_dimensions(&tmpfile_str, limits)?;
+    }
 
-                mime::IMAGE_JPEG
-            }
-            (Some(Format::Png), MediaType::Png) | (None, MediaType::Png) => {
-                validate_format(&tmpfile_str, "PNG")?;
+    let content_type = match (prescribed_format, media_type) {
+        (_, MediaType::Gif) => {
+            let newfile = tmp_file();
+            validate_gif(tmpfile, &newfile, limits)?;
 
-                meta.clear();
-                meta.save_to_file(&tmpfile)?;
+            mime::IMAGE_GIF
+        }
+        (Some(Format::Jpeg), MediaType::Jpeg) | (None, MediaType::Jpeg) => {
+            validate_format(&tmpfile_str, "JPEG")?;
 
-                mime::IMAGE_PNG
-            }
-            (Some(Format::Webp), MediaType::Other(webp)) | (None, MediaType::Other(webp))
-                if webp == "image/webp" =>
-            {
-                let newfile = tmp_file();
-                let newfile_str = ptos(&newfile)?;
-                // clean metadata by writing new webp, since exiv2 doesn't support webp yet
-                {
-                    let wand = MagickWand::new();
+            meta.clear();
+            meta.save_to_file(tmpfile)?;
 
-                    debug!("reading");
-                    wand.op(|w| w.read_image(&tmpfile_str))?;
+            mime::IMAGE_JPEG
+        }
+        (Some(Format::Png), MediaType::Png) | (None, MediaType::Png) => {
+            validate_format(&tmpfile_str, "PNG")?;
 
-                    if wand.op(|w| w.get_image_format())? != "WEBP" {
-                        return Err(UploadError::UnsupportedFormat);
-                    }
+            meta.clear();
+            meta.save_to_file(tmpfile)?;
 
-                    wand.op(|w| w.write_image(&newfile_str))?;
-                }
+            mime::IMAGE_PNG
+        }
+        (Some(Format::Webp), MediaType::Other(webp)) | (None, MediaType::Other(webp))
+            if webp == "image/webp" =>
+        {
+            let newfile = tmp_file();
+            let newfile_str = ptos(&newfile)?;
+            // clean metadata by writing new webp, since exiv2 doesn't support webp yet
+            {
+                let wand = MagickWand::new();
+
+                debug!("reading");
+                wand.op(|w| w.read_image(&tmpfile_str))?;
 
-                std::fs::rename(&newfile, &tmpfile)?;
+                if wand.op(|w| w.get_image_format())? != "WEBP" {
+                    return Err(UploadError::UnsupportedFormat);
+                }
 
-                image_webp()
+                wand.op(|w| w.write_image(&newfile_str))?;
             }
-            (Some(format), _) => {
-                let newfile = tmp_file();
-                let newfile_str = ptos(&newfile)?;
-                {
-                    let mut wand = MagickWand::new();
 
-                    debug!("reading: {}", tmpfile_str);
-                    wand.op(|w| w.read_image(&tmpfile_str))?;
+            std::fs::rename(&newfile, tmpfile)?;
 
-                    wand.op_mut(|w| w.set_image_format(format.to_magick_format()))?;
+            image_webp()
+        }
+        (Some(format), _) => {
+            let newfile = tmp_file();
+            let newfile_str = ptos(&newfile)?;
+            {
+                let mut wand = MagickWand::new();
 
-                    debug!("writing: {}", newfile_str);
-                    wand.op(|w| w.write_image(&newfile_str))?;
-                }
+                debug!("reading: {}", tmpfile_str);
+                wand.op(|w| w.read_image(&tmpfile_str))?;
 
-                std::fs::rename(&newfile, &tmpfile)?;
+                wand.op_mut(|w| w.set_image_format(format.to_magick_format()))?;
 
-                format.to_mime()
-            }
-            (_, media_type) => {
-                warn!("Unsupported media type, {}", media_type);
-                return Err(UploadError::UnsupportedFormat);
+                debug!("writing: {}", newfile_str);
+                wand.op(|w| w.write_image(&newfile_str))?;
             }
-        };
 
-        drop(entered);
-        Ok(content_type) as Result<mime::Mime, UploadError>
-    })
-    .await?;
+            std::fs::rename(&newfile, tmpfile)?;
+
+            format.to_mime()
+        }
+        (_, media_type) => {
+            warn!("Unsupported media type, {}", media_type);
+            return Err(UploadError::UnsupportedFormat);
+        }
+    };
 
     Ok(content_type)
 }
 
 #[instrument]
-fn validate_gif(from: &PathBuf, to: &PathBuf) -> Result<(), GifError> {
+fn validate_gif(from: &PathBuf, to: &PathBuf, limits: &ImageLimits) -> Result<(), GifError> {
     debug!("Transmuting GIF");
     use gif::{Parameter, SetParameter};
 
@@ -190,6 +336,10 @@ fn validate_gif(from: &PathBuf, to: &PathBuf) -> Result<(), GifError> {
 
     let width = reader.width();
     let height = reader.height();
+    limits
+        .check(width.into(), height.into())
+        .map_err(GifError::TooLarge)?;
+
     let global_palette = reader.global_palette().unwrap_or(&[]);
 
     let mut writer = BufWriter::new(File::create(to)?);
@@ -197,9 +347,30 @@ fn validate_gif(from: &PathBuf, to: &PathBuf) -> Result<(), GifError> {
 
     gif::Repeat::Infinite.set_param(&mut encoder)?;
 
-    while let Some(frame) = reader.read_next_frame()? {
+    let mut frame_count = 0;
+    // Frames can be larger than the logical screen, so each one is checked before it's decoded
+    while let Some(frame) = reader.next_frame_info()?.cloned() {
+        frame_count += 1;
+        if frame_count > limits.max_frame_count {
+            return Err(GifError::TooLarge(format!(
+                "more than {} frames",
+                limits.max_frame_count
+            )));
+        }
+
+        limits
+            .check(frame.width.into(), frame.height.into())
+            .map_err(|e| GifError::TooLarge(format!("frame {}, {}", frame_count, e)))?;
+
+        let mut frame = frame;
+        let mut buffer = vec![0; reader.buffer_size()];
+        reader.read_into_buffer(&mut buffer)?;
+        frame.buffer = Cow::Owned(buffer);
+        // read_into_buffer already deinterlaced the pixels
+        frame.interlaced = false;
+
         trace!("Writing frame");
-        encoder.write_frame(frame)?;
+        encoder.write_frame(&frame)?;
     }
 
     drop(encoder);
@@ -208,3 +379,139 @@ fn validate_gif(from: &PathBuf, to: &PathBuf) -> Result<(), GifError> {
     std::fs::rename(to, from)?;
     Ok(())
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Once;
+
+    static MAGICK_INIT: Once = Once::new();
+
+    // ImageMagick reads its limits once, so every test in this process shares these
+    pub(crate) fn init() {
+        MAGICK_INIT.call_once(|| {
+            std::env::set_var("MAGICK_MEMORY_LIMIT", "1MiB");
+            std::env::set_var("MAGICK_MAP_LIMIT", "1MiB");
+            std::env::set_var("MAGICK_DISK_LIMIT", "1MiB");
+
+            magick_rust::magick_wand_genesis();
+        });
+    }
+
+    fn crc32(bytes: &[u8]) -> u32 {
+        !bytes.iter().fold(!0u32, |crc, byte| {
+            (0..8).fold(crc ^ u32::from(*byte), |crc, _| {
+                (crc >> 1) ^ (0xedb8_8320 & (!(crc & 1)).wrapping_add(1))
+            })
+        })
+    }
+
+    fn chunk(png: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = png.len();
+        png.extend_from_slice(kind);
+        png.extend_from_slice(data);
+        let crc = crc32(&png[start..]);
+        png.extend_from_slice(&crc.to_be_bytes());
+    }
+
+    // A tiny file claiming a 20000x20000 RGBA canvas, which ImageMagick allocates before it
+    // notices the pixel data is missing
+    fn large_canvas_png() -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&20_000u32.to_be_bytes());
+        header.extend_from_slice(&20_000u32.to_be_bytes());
+        // 8 bits per channel, RGBA, default compression, filtering and no interlacing
+        header.extend_from_slice(&[8, 6, 0, 0, 0]);
+        chunk(&mut png, b"IHDR", &header);
+
+        // A zlib stream holding one stored block with a single filter byte
+        chunk(
+            &mut png,
+            b"IDAT",
+            &[
+                0x78, 0x01, 0x01, 0x01, 0x00, 0xfe, 0xff, 0x00, 0x00, 0x01, 0x00, 0x01,
+            ],
+        );
+        chunk(&mut png, b"IEND", &[]);
+
+        png
+    }
+
+    #[test]
+    fn crc32_matches_png_iend() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+    }
+
+    #[test]
+    fn large_canvas_hits_resource_limit() {
+        init();
+
+        let tmpfile = std::env::temp_dir().join(format!("{}.png", uuid::Uuid::new_v4()));
+        std::fs::write(&tmpfile, large_canvas_png()).unwrap();
+
+        // The dimensions are allowed, so only ImageMagick's own limits can stop it
+        let limits = ImageLimits {
+            max_width: 100_000,
+            max_height: 100_000,
+            max_area: 1_000_000_000,
+            max_frame_count: 100,
+        };
+        let res = validate_file(&tmpfile, None, &limits);
+        std::fs::remove_file(&tmpfile).unwrap();
+
+        assert!(
+            matches!(res, Err(UploadError::ResourceLimit(_))),
+            "expected a resource limit error, got {:?}",
+            res
+        );
+    }
+
+    // A 1x1 logical screen holding a single 60000x60000 frame with almost no pixel data
+    fn oversized_frame_gif() -> Vec<u8> {
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(&1u16.to_le_bytes());
+        gif.extend_from_slice(&1u16.to_le_bytes());
+        // No global color table, background color and aspect ratio unset
+        gif.extend_from_slice(&[0, 0, 0]);
+
+        gif.push(0x2c);
+        gif.extend_from_slice(&[0, 0, 0, 0]);
+        gif.extend_from_slice(&60_000u16.to_le_bytes());
+        gif.extend_from_slice(&60_000u16.to_le_bytes());
+        // A two color local color table
+        gif.push(0x80);
+        gif.extend_from_slice(&[0, 0, 0, 255, 255, 255]);
+
+        // LZW data holding a clear code followed by the end code
+        gif.extend_from_slice(&[2, 1, 0x2c, 0]);
+        gif.push(0x3b);
+
+        gif
+    }
+
+    #[test]
+    fn oversized_gif_frames_are_rejected() {
+        let from = std::env::temp_dir().join(format!("{}.gif", uuid::Uuid::new_v4()));
+        let to = std::env::temp_dir().join(format!("{}.gif", uuid::Uuid::new_v4()));
+        std::fs::write(&from, oversized_frame_gif()).unwrap();
+
+        let limits = ImageLimits {
+            max_width: 10_000,
+            max_height: 10_000,
+            max_area: 40_000_000,
+            max_frame_count: 100,
+        };
+        let res = validate_gif(&from, &to, &limits);
+        std::fs::remove_file(&from).unwrap();
+        let _ = std::fs::remove_file(&to);
+
+        assert!(
+            matches!(res, Err(GifError::TooLarge(_))),
+            "expected the frame to be rejected, got {:?}",
+            res
+        );
+    }
+}